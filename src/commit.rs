@@ -1,10 +1,20 @@
 use crate::repo::{self, Repo};
-use git2::{Index, IndexEntry, MergeOptions, Oid};
+use git2::{build::CheckoutBuilder, Index, IndexEntry, MergeOptions, Oid};
+use std::{fs, io, path::Path, process};
 
 #[derive(Debug, derive_more::Display, derive_more::From, derive_more::Error)]
 pub enum Error {
     Git(git2::Error),
     Repo(repo::Error),
+    IO(io::Error),
+
+    #[display(fmt = "No merge tool configured, set merge.tool and mergetool.<tool>.cmd")]
+    MergeToolNotConfigured,
+
+    #[display(fmt = "Merge tool failed to resolve {path}")]
+    MergeToolFailed {
+        path: String,
+    },
 
     #[display(fmt = "Could not cherry-pick {cherry} onto {commit} in a conflict-free way")]
     CherryPickError {
@@ -13,6 +23,94 @@ pub enum Error {
     },
 }
 
+/// Merge strategy option, akin to `git cherry-pick -X <option>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StrategyOption {
+    Ours,
+    Theirs,
+    IgnoreAllSpace,
+    IgnoreSpaceChange,
+    IgnoreSpaceAtEol,
+    Patience,
+    Minimal,
+}
+
+impl StrategyOption {
+    fn apply(&self, merge_options: &mut MergeOptions) {
+        match self {
+            StrategyOption::Ours => merge_options.file_favor(git2::FileFavor::Ours),
+            StrategyOption::Theirs => merge_options.file_favor(git2::FileFavor::Theirs),
+            StrategyOption::IgnoreAllSpace => merge_options.ignore_whitespace(true),
+            StrategyOption::IgnoreSpaceChange => merge_options.ignore_whitespace_change(true),
+            StrategyOption::IgnoreSpaceAtEol => merge_options.ignore_whitespace_eol(true),
+            StrategyOption::Patience => merge_options.patience(true),
+            StrategyOption::Minimal => merge_options.minimal(true),
+        };
+    }
+}
+
+impl std::str::FromStr for StrategyOption {
+    type Err = String;
+
+    fn from_str(option: &str) -> Result<Self, Self::Err> {
+        match option {
+            "ours" => Ok(StrategyOption::Ours),
+            "theirs" => Ok(StrategyOption::Theirs),
+            "ignore-all-space" | "whitespace=ignore" => Ok(StrategyOption::IgnoreAllSpace),
+            "ignore-space-change" | "whitespace=ignore-change" => {
+                Ok(StrategyOption::IgnoreSpaceChange)
+            }
+            "ignore-space-at-eol" | "whitespace=ignore-eol" => Ok(StrategyOption::IgnoreSpaceAtEol),
+            "patience" | "diff-algorithm=patience" => Ok(StrategyOption::Patience),
+            "diff-algorithm=minimal" => Ok(StrategyOption::Minimal),
+            _ => Err(format!("Unknown strategy option {option:?}")),
+        }
+    }
+}
+
+/// Commit message trailer such as `Signed-off-by: Name <email>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trailer {
+    pub key: String,
+    pub value: String,
+}
+
+impl std::str::FromStr for Trailer {
+    type Err = String;
+
+    fn from_str(trailer: &str) -> Result<Self, Self::Err> {
+        let (key, value) = trailer
+            .split_once([':', '='])
+            .ok_or_else(|| format!("Expected \"key: value\", got {trailer:?}"))?;
+
+        Ok(Trailer {
+            key: key.trim().to_string(),
+            value: value.trim().to_string(),
+        })
+    }
+}
+
+/// Append the trailers to the message, extending an existing trailer block if there is one.
+pub fn append_trailers(message: &str, trailers: &[Trailer]) -> String {
+    if trailers.is_empty() {
+        return message.to_string();
+    }
+
+    let mut message = message.trim_end().to_string();
+
+    let has_trailers = git2::message_trailers_strs(message.as_str())
+        .map(|existing| existing.len() > 0)
+        .unwrap_or(false);
+
+    message.push_str(if has_trailers { "\n" } else { "\n\n" });
+
+    for trailer in trailers {
+        message.push_str(format!("{}: {}\n", trailer.key, trailer.value).as_str());
+    }
+
+    message
+}
+
 #[derive(
     Clone,
     derive_more::From,
@@ -30,24 +128,130 @@ fn remove_conflict(index: &Index, entry: &IndexEntry) {
 
     unsafe {
         let funky_index: &MyIndex = std::mem::transmute(index);
-        let path = entry.path.as_ptr();
-        let result = libgit2_sys::git_index_conflict_remove(funky_index.raw, path.cast());
+        // The path of an index entry is not NUL-terminated
+        let path = std::ffi::CString::new(entry.path.as_slice()).expect("Path contains NUL");
+        let result = libgit2_sys::git_index_conflict_remove(funky_index.raw, path.as_ptr());
         assert_eq!(result, 0);
     }
 }
 
+/// External program used to resolve conflicts, configured like `git mergetool` through
+/// `merge.tool` and `mergetool.<tool>.cmd`
+#[derive(Debug, Clone)]
+pub struct MergeTool {
+    cmd: String,
+}
+
+impl MergeTool {
+    pub fn from_config(config: &git2::Config) -> Result<Self, Error> {
+        let get = |name: &str| match config.get_string(name) {
+            Ok(value) => Ok(value),
+            Err(err) if err.code() == git2::ErrorCode::NotFound => {
+                Err(Error::MergeToolNotConfigured)
+            }
+            Err(err) => Err(err.into()),
+        };
+
+        let tool = get("merge.tool")?;
+        let cmd = get(format!("mergetool.{tool}.cmd").as_str())?;
+
+        Ok(Self { cmd })
+    }
+
+    /// Let the user merge both sides of a conflicting file, returning the blob of the result.
+    fn resolve(
+        &self,
+        repo: &Repo,
+        index: &mut Index,
+        ancestor: Option<&IndexEntry>,
+        ours: &IndexEntry,
+        theirs: &IndexEntry,
+    ) -> Result<Oid, Error> {
+        // The files are kept in a directory of our own inside .git, like COMMIT_EDITMSG
+        let dir = repo.path().join(format!("MERGETOOL_{}", process::id()));
+        fs::create_dir(&dir)?;
+
+        let result = self.resolve_in(&dir, repo, index, ancestor, ours, theirs);
+        let _ = fs::remove_dir_all(&dir);
+
+        result
+    }
+
+    fn resolve_in(
+        &self,
+        dir: &Path,
+        repo: &Repo,
+        index: &mut Index,
+        ancestor: Option<&IndexEntry>,
+        ours: &IndexEntry,
+        theirs: &IndexEntry,
+    ) -> Result<Oid, Error> {
+        let path = String::from_utf8_lossy(&theirs.path).to_string();
+        let file_name = path.rsplit('/').next().unwrap_or(path.as_str());
+
+        let write_stage = |stage: &str, entry: Option<&IndexEntry>| -> Result<_, Error> {
+            let file = dir.join(format!("{stage}_{file_name}"));
+            let contents = match entry {
+                Some(entry) => repo.0.find_blob(entry.id)?.content().to_vec(),
+                None => Vec::new(),
+            };
+            fs::write(&file, contents)?;
+            Ok(file)
+        };
+
+        let base = write_stage("BASE", ancestor)?;
+        let local = write_stage("LOCAL", Some(ours))?;
+        let remote = write_stage("REMOTE", Some(theirs))?;
+
+        // Checking out the conflicting entry produces the file with conflict markers that git
+        // would leave in the working directory.
+        let mut checkout = CheckoutBuilder::new();
+        checkout
+            .target_dir(dir)
+            .path(path.as_str())
+            .update_index(false)
+            .force();
+        repo.0.checkout_index(Some(index), Some(&mut checkout))?;
+        let merged = dir.join(&path);
+
+        // Like git, the command is run by the shell with the file names in the environment.
+        let status = process::Command::new("sh")
+            .arg("-c")
+            .arg(&self.cmd)
+            .env("BASE", &base)
+            .env("LOCAL", &local)
+            .env("REMOTE", &remote)
+            .env("MERGED", &merged)
+            .status()?;
+
+        if !status.success() {
+            return Err(Error::MergeToolFailed { path });
+        }
+
+        Ok(repo.0.blob(&fs::read(&merged)?)?)
+    }
+}
+
 impl<'a> Commit<'a> {
+    #[allow(clippy::too_many_arguments)]
     pub fn cherry_pick(
         &self,
         repo: &'a Repo,
         cherry: &Self,
         sign: bool,
         forceful: bool,
+        strategy_options: &[StrategyOption],
+        mergetool: Option<&MergeTool>,
+        committer: Option<&git2::Signature>,
     ) -> Result<Commit<'a>, Error> {
         assert_eq!(cherry.0.parent_count(), 1);
 
         let mut merge_options = MergeOptions::new();
 
+        for option in strategy_options {
+            option.apply(&mut merge_options);
+        }
+
         if forceful {
             merge_options.file_favor(git2::FileFavor::Theirs);
         }
@@ -64,13 +268,27 @@ impl<'a> Commit<'a> {
                 });
             }
 
-            let new_entries = new_index
-                .conflicts()?
+            // Need to collect to relinquish the reference to [new_index].
+            let conflicts = new_index.conflicts()?.collect::<Result<Vec<_>, _>>()?;
+
+            let new_entries = conflicts
+                .into_iter()
                 .map(|conflict| {
-                    let conflict = conflict?;
-                    match (conflict.our, conflict.their) {
+                    match (mergetool, conflict.ancestor, conflict.our, conflict.their) {
                         // A new file has been created
-                        (None, Some(index)) => Ok(index),
+                        (_, _, None, Some(index)) => Ok(index),
+
+                        // Both sides changed the file, let the user merge them
+                        (Some(tool), ancestor, Some(ours), Some(mut theirs)) => {
+                            theirs.id = tool.resolve(
+                                repo,
+                                &mut new_index,
+                                ancestor.as_ref(),
+                                &ours,
+                                &theirs,
+                            )?;
+                            Ok(theirs)
+                        }
 
                         _ => Err(Error::CherryPickError {
                             cherry: cherry.id(),
@@ -78,7 +296,6 @@ impl<'a> Commit<'a> {
                         }),
                     }
                 })
-                // Need to collect to relinquish the reference to [index].
                 .collect::<Result<Vec<_>, _>>()?;
 
             for mut entry in new_entries {
@@ -92,10 +309,15 @@ impl<'a> Commit<'a> {
 
         let new_tree = repo.0.find_tree(new_index.write_tree_to(&repo.0)?)?;
 
+        let committer = match committer {
+            Some(committer) => committer.to_owned(),
+            None => cherry.0.committer().to_owned(),
+        };
+
         let new_commit = if sign {
             repo.commit_signed(
                 &cherry.0.author(),
-                &cherry.0.committer(),
+                &committer,
                 cherry.0.message().unwrap_or(""),
                 &new_tree,
                 [self],
@@ -103,7 +325,7 @@ impl<'a> Commit<'a> {
         } else {
             repo.commit(
                 &cherry.0.author(),
-                &cherry.0.committer(),
+                &committer,
                 cherry.0.message().unwrap_or(""),
                 &new_tree,
                 [self],
@@ -113,7 +335,318 @@ impl<'a> Commit<'a> {
         Ok(new_commit)
     }
 
+    /// Replace the tree of the commit, and optionally its committer
+    pub fn amend_tree(
+        &self,
+        repo: &'a Repo,
+        new_tree: &git2::Tree,
+        committer: Option<&git2::Signature>,
+    ) -> Result<Commit<'a>, Error> {
+        let new_commit = self
+            .0
+            .amend(None, None, committer, None, None, Some(new_tree))?;
+        let new_commit = repo.0.find_commit(new_commit)?;
+        Ok(Commit(new_commit))
+    }
+
+    pub fn amend_message(&self, repo: &'a Repo, new_message: &str) -> Result<Commit<'a>, Error> {
+        let new_commit = self
+            .0
+            .amend(None, None, None, None, Some(new_message), None)?;
+        let new_commit = repo.0.find_commit(new_commit)?;
+        Ok(Commit(new_commit))
+    }
+
+    /// Key and value of every trailer at the end of the commit message
+    pub fn message_trailers(&self) -> Result<Vec<(String, String)>, git2::Error> {
+        let trailers = git2::message_trailers_strs(self.0.message().unwrap_or(""))?;
+        let trailers = trailers
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        Ok(trailers)
+    }
+
     pub fn id(&self) -> git2::Oid {
         self.0.id()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conflicting_commits(repo: &Repo) -> (Commit<'_>, Commit<'_>) {
+        let base = repo.commit_files(None, &[("file", "a\n")]);
+        let ours = repo.commit_files(Some(&base), &[("file", "ours\n")]);
+        let theirs = repo.commit_files(Some(&base), &[("file", "theirs\n")]);
+        (ours, theirs)
+    }
+
+    #[test]
+    fn amend_tree_keeps_everything_else() {
+        let (repo, _dir) = Repo::temporary();
+        let base = repo.commit_files(None, &[("file", "a\n")]);
+        let commit = repo.commit_files(Some(&base), &[("file", "b\n")]);
+
+        let amended = commit
+            .amend_tree(&repo, &base.tree().unwrap(), None)
+            .unwrap();
+        assert_eq!(amended.tree_id(), base.tree_id());
+        assert_eq!(amended.message(), commit.message());
+        assert_eq!(amended.author().name(), commit.author().name());
+        assert_eq!(amended.parent_id(0).unwrap(), base.id());
+
+        let committer = git2::Signature::now("Jane Doe", "jane@example.com").unwrap();
+        let amended = commit
+            .amend_tree(&repo, &commit.tree().unwrap(), Some(&committer))
+            .unwrap();
+        assert_eq!(amended.committer().name(), Some("Jane Doe"));
+        assert_eq!(amended.author().name(), commit.author().name());
+    }
+
+    #[test]
+    fn message_trailers_are_parsed() {
+        let (repo, _dir) = Repo::temporary();
+        let commit = repo.commit_files(None, &[("file", "a\n")]);
+        assert!(commit.message_trailers().unwrap().is_empty());
+
+        let message =
+            "Subject\n\nBody: not a trailer\n\nReviewed-by: Jane <jane@example.com>\nFixes: #12\n";
+        let commit = commit.amend_message(&repo, message).unwrap();
+        assert_eq!(
+            commit.message_trailers().unwrap(),
+            [
+                (
+                    "Reviewed-by".to_string(),
+                    "Jane <jane@example.com>".to_string()
+                ),
+                ("Fixes".to_string(), "#12".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn amend_message_keeps_the_tree() {
+        let (repo, _dir) = Repo::temporary();
+        let base = repo.commit_files(None, &[("file", "a\n")]);
+        let commit = repo.commit_files(Some(&base), &[("file", "b\n")]);
+
+        let amended = commit.amend_message(&repo, "New message\n").unwrap();
+        assert_eq!(amended.message(), Some("New message\n"));
+        assert_eq!(amended.tree_id(), commit.tree_id());
+        assert_eq!(amended.parent_id(0).unwrap(), base.id());
+    }
+
+    #[test]
+    fn strategy_option_from_str() {
+        let parse = |option: &str| option.parse::<StrategyOption>();
+
+        assert_eq!(parse("ours"), Ok(StrategyOption::Ours));
+        assert_eq!(parse("theirs"), Ok(StrategyOption::Theirs));
+        assert_eq!(
+            parse("ignore-all-space"),
+            Ok(StrategyOption::IgnoreAllSpace)
+        );
+        assert_eq!(
+            parse("whitespace=ignore"),
+            Ok(StrategyOption::IgnoreAllSpace)
+        );
+        assert_eq!(
+            parse("whitespace=ignore-change"),
+            Ok(StrategyOption::IgnoreSpaceChange)
+        );
+        assert_eq!(
+            parse("ignore-space-at-eol"),
+            Ok(StrategyOption::IgnoreSpaceAtEol)
+        );
+        assert_eq!(
+            parse("diff-algorithm=patience"),
+            Ok(StrategyOption::Patience)
+        );
+        assert_eq!(parse("diff-algorithm=minimal"), Ok(StrategyOption::Minimal));
+        assert!(parse("recursive").is_err());
+        assert!(parse("").is_err());
+    }
+
+    #[test]
+    fn strategy_options_resolve_conflicts() {
+        let (repo, _dir) = Repo::temporary();
+        let (ours, theirs) = conflicting_commits(&repo);
+
+        let pick = |options: &[StrategyOption]| {
+            let picked = ours.cherry_pick(&repo, &theirs, false, false, options, None, None)?;
+            let entry = picked.tree()?.get_path(Path::new("file"))?;
+            let blob = repo.0.find_blob(entry.id())?;
+            Ok::<_, Error>(String::from_utf8_lossy(blob.content()).to_string())
+        };
+
+        assert_eq!(pick(&[StrategyOption::Ours]).unwrap(), "ours\n");
+        assert_eq!(pick(&[StrategyOption::Theirs]).unwrap(), "theirs\n");
+        assert!(pick(&[]).is_err());
+    }
+
+    #[test]
+    fn ignore_space_change_avoids_whitespace_conflicts() {
+        let (repo, _dir) = Repo::temporary();
+        let base = repo.commit_files(None, &[("file", "a\nb\nc\n")]);
+        let ours = repo.commit_files(Some(&base), &[("file", "a \nb\nc\n")]);
+        let theirs = repo.commit_files(Some(&base), &[("file", "a  \nb\nc\nd\n")]);
+
+        let options = [StrategyOption::IgnoreSpaceChange];
+        assert!(ours
+            .cherry_pick(&repo, &theirs, false, false, &[], None, None)
+            .is_err());
+        assert!(ours
+            .cherry_pick(&repo, &theirs, false, false, &options, None, None)
+            .is_ok());
+    }
+
+    #[test]
+    fn trailer_from_str() {
+        let trailer = |key: &str, value: &str| {
+            Ok(Trailer {
+                key: key.to_string(),
+                value: value.to_string(),
+            })
+        };
+
+        assert_eq!(
+            "Signed-off-by: Jane <jane@example.com>".parse(),
+            trailer("Signed-off-by", "Jane <jane@example.com>")
+        );
+        assert_eq!("Fixes=#12".parse(), trailer("Fixes", "#12"));
+        assert_eq!(" Ref :  a:b ".parse(), trailer("Ref", "a:b"));
+        assert!("No separator".parse::<Trailer>().is_err());
+    }
+
+    #[test]
+    fn append_trailers_to_messages() {
+        let trailers = [
+            "Reviewed-by: Jane <jane@example.com>".parse().unwrap(),
+            "Fixes: #12".parse().unwrap(),
+        ];
+
+        assert_eq!(append_trailers("Subject\n", &[]), "Subject\n");
+        assert_eq!(
+            append_trailers("Subject\n\nBody\n\n", &trailers),
+            "Subject\n\nBody\n\nReviewed-by: Jane <jane@example.com>\nFixes: #12\n"
+        );
+
+        // An existing trailer block is extended
+        assert_eq!(
+            append_trailers(
+                "Subject\n\nSigned-off-by: Joe <joe@example.com>\n",
+                &trailers[1..]
+            ),
+            "Subject\n\nSigned-off-by: Joe <joe@example.com>\nFixes: #12\n"
+        );
+    }
+
+    #[test]
+    fn cherry_pick_with_committer() {
+        let (repo, _dir) = Repo::temporary();
+        let base = repo.commit_files(None, &[("a", "1\n")]);
+        let onto = repo.commit_files(Some(&base), &[("b", "1\n")]);
+        let cherry = repo.commit_files(Some(&base), &[("c", "1\n")]);
+        let committer = git2::Signature::now("Jane Doe", "jane@example.com").unwrap();
+
+        let picked = onto
+            .cherry_pick(&repo, &cherry, false, false, &[], None, Some(&committer))
+            .unwrap();
+        assert_eq!(picked.committer().name(), Some("Jane Doe"));
+        assert_eq!(picked.author().name(), cherry.author().name());
+
+        // Without an override, the committer of the cherry is kept
+        let picked = onto
+            .cherry_pick(&repo, &cherry, false, false, &[], None, None)
+            .unwrap();
+        assert_eq!(picked.committer().name(), cherry.committer().name());
+    }
+
+    fn pick_with_tool(repo: &Repo, cmd: &str) -> Result<String, Error> {
+        let (ours, theirs) = conflicting_commits(repo);
+        let tool = MergeTool {
+            cmd: cmd.to_string(),
+        };
+
+        let picked = ours.cherry_pick(repo, &theirs, false, false, &[], Some(&tool), None)?;
+        let entry = picked.tree()?.get_path(Path::new("file"))?;
+        let blob = repo.0.find_blob(entry.id())?;
+        Ok(String::from_utf8_lossy(blob.content()).to_string())
+    }
+
+    #[test]
+    fn mergetool_result_is_used() {
+        let (repo, _dir) = Repo::temporary();
+        let contents = pick_with_tool(&repo, r#"cp "$REMOTE" "$MERGED""#).unwrap();
+        assert_eq!(contents, "theirs\n");
+    }
+
+    #[test]
+    fn mergetool_starts_from_conflict_markers() {
+        let (repo, _dir) = Repo::temporary();
+        let contents = pick_with_tool(&repo, "true").unwrap();
+        assert!(contents.contains("<<<<<<<"), "{contents}");
+        assert!(contents.contains("ours\n"), "{contents}");
+        assert!(contents.contains("theirs\n"), "{contents}");
+    }
+
+    #[test]
+    fn mergetool_failure_aborts() {
+        let (repo, _dir) = Repo::temporary();
+        let result = pick_with_tool(&repo, "false");
+        assert!(matches!(result, Err(Error::MergeToolFailed { .. })));
+    }
+
+    #[test]
+    fn mergetool_files_are_removed() {
+        let (repo, _dir) = Repo::temporary();
+        pick_with_tool(&repo, "true").unwrap();
+
+        let leftovers = fs::read_dir(repo.path())
+            .unwrap()
+            .filter_map(Result::ok)
+            .filter(|entry| {
+                entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with("MERGETOOL_")
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+    }
+
+    #[test]
+    fn remove_conflict_takes_only_that_path() {
+        let (repo, _dir) = Repo::temporary();
+        let base = repo.commit_files(None, &[("file", "a\n"), ("file2", "a\n")]);
+        let ours = repo.commit_files(Some(&base), &[("file", "ours\n"), ("file2", "ours\n")]);
+        let theirs = repo.commit_files(Some(&base), &[("file", "theirs\n"), ("file2", "theirs\n")]);
+        let index = repo
+            .0
+            .cherrypick_commit(&theirs.0, &ours.0, 0, None)
+            .unwrap();
+
+        // Entry paths are not NUL-terminated, and "file" is a prefix of the other path
+        let entry = index.get_path(Path::new("file"), 2).unwrap();
+        assert_eq!(entry.path, b"file");
+        remove_conflict(&index, &entry);
+
+        let conflicts = index
+            .conflicts()
+            .unwrap()
+            .map(|conflict| conflict.unwrap().our.unwrap().path)
+            .collect::<Vec<_>>();
+        assert_eq!(conflicts, [b"file2".to_vec()]);
+    }
+
+    #[test]
+    fn conflicts_without_mergetool_fail() {
+        let (repo, _dir) = Repo::temporary();
+        let (ours, theirs) = conflicting_commits(&repo);
+
+        let result = ours.cherry_pick(&repo, &theirs, false, false, &[], None, None);
+        assert!(matches!(result, Err(Error::CherryPickError { .. })));
+    }
+}
@@ -1,11 +1,20 @@
 use core::fmt;
-use git2::{Diff, Patch};
+use git2::{Delta, Diff, FileMode, Patch};
+use std::path::Path;
 use termion::color::{Cyan, Fg, Green, Red, Reset};
 
 enum DiffLine {
-    HunkStart { offset: String, line: String },
+    HunkStart {
+        offset: String,
+        line: String,
+    },
     Deletion(String),
     Addition(String),
+    Renamed {
+        from: String,
+        to: String,
+        similarity: Option<String>,
+    },
     Other(String),
 }
 
@@ -37,6 +46,17 @@ impl fmt::Display for DiffLine {
             }
             DiffLine::Deletion(line) => write!(f, "{}-{line}{}", Fg(Red), Fg(Reset)),
             DiffLine::Addition(line) => write!(f, "{}+{line}{}", Fg(Green), Fg(Reset)),
+            DiffLine::Renamed {
+                from,
+                to,
+                similarity,
+            } => {
+                write!(f, "{}renamed from a/{from} to b/{to}", Fg(Cyan))?;
+                if let Some(similarity) = similarity {
+                    write!(f, " (similarity {similarity})")?;
+                }
+                write!(f, "{}", Fg(Reset))
+            }
             DiffLine::Other(line) => line.fmt(f),
         }
     }
@@ -49,12 +69,68 @@ pub struct PrettyPatch {
 impl PrettyPatch {
     pub fn new(patch: &mut Patch) -> Result<Self, git2::Error> {
         let buffer = patch.to_buf()?;
-        let lines = buffer
-            .as_str()
-            .unwrap_or("")
-            .lines()
-            .map(DiffLine::new)
-            .collect::<Vec<_>>();
+        let text = buffer.as_str().unwrap_or("");
+
+        let mut lines = Vec::new();
+
+        let delta = patch.delta();
+        if delta.status() == Delta::Renamed {
+            let path = |file: git2::DiffFile| {
+                file.path()
+                    .map(|path| path.display().to_string())
+                    .unwrap_or_default()
+            };
+
+            // git2 does not expose the similarity score, but it is part of the patch header.
+            let similarity = text
+                .lines()
+                .find_map(|line| line.strip_prefix("similarity index "))
+                .map(|similarity| similarity.to_string());
+
+            lines.push(DiffLine::Renamed {
+                from: path(delta.old_file()),
+                to: path(delta.new_file()),
+                similarity,
+            });
+        }
+
+        let is_submodule = [delta.old_file(), delta.new_file()]
+            .iter()
+            .any(|file| file.mode() == FileMode::Commit);
+
+        if is_submodule {
+            // Like git, show the change of a submodule pointer on a single line instead of a hunk
+            // that removes and adds "Subproject commit" lines.
+            lines.extend(
+                text.lines()
+                    .take_while(|line| !line.starts_with("@@"))
+                    .map(DiffLine::new),
+            );
+
+            let (old, new) = (delta.old_file().id(), delta.new_file().id());
+            let range = match (old.is_zero(), new.is_zero()) {
+                (true, _) => new.to_string(),
+                (_, true) => old.to_string(),
+                _ => format!("{old}..{new}"),
+            };
+            lines.push(DiffLine::Other(format!("Subproject commit {range}")));
+
+            return Ok(Self { lines });
+        }
+
+        // The rename header above replaces the raw one
+        let is_rename_header = |line: &&str| {
+            delta.status() == Delta::Renamed
+                && ["similarity index ", "rename from ", "rename to "]
+                    .iter()
+                    .any(|prefix| line.starts_with(prefix))
+        };
+
+        lines.extend(
+            text.lines()
+                .filter(|line| !is_rename_header(line))
+                .map(DiffLine::new),
+        );
         Ok(Self { lines })
     }
 }
@@ -71,19 +147,26 @@ impl fmt::Display for PrettyPatch {
 
 pub struct PrettyDiff {
     patches: Vec<PrettyPatch>,
+    omitted: usize,
 }
 
 impl PrettyDiff {
-    pub fn new(diff: &Diff) -> Result<Self, git2::Error> {
+    pub fn new(diff: &Diff, max_files: Option<usize>) -> Result<Self, git2::Error> {
         let stats = diff.stats()?;
-        let patches = (0..stats.files_changed())
+        let files_changed = stats.files_changed();
+        let num_files = max_files.map_or(files_changed, |max| max.min(files_changed));
+
+        let patches = (0..num_files)
             .filter_map(|index| {
                 let mut patch = git2::Patch::from_diff(diff, index).ok()??;
                 PrettyPatch::new(&mut patch).ok()
             })
             .collect::<Vec<_>>();
 
-        Ok(Self { patches })
+        Ok(Self {
+            patches,
+            omitted: files_changed - num_files,
+        })
     }
 }
 
@@ -93,6 +176,10 @@ impl fmt::Display for PrettyDiff {
             writeln!(f, "{}", patch)?;
         }
 
+        if self.omitted > 0 {
+            writeln!(f, "... and {} more files", self.omitted)?;
+        }
+
         Ok(())
     }
 }
@@ -111,3 +198,209 @@ pub fn render(diff: &Diff) -> Result<String, git2::Error> {
 
     Ok(buffers)
 }
+
+/// Hunks of the deltas in the diff whose new path is `path`, each given by its header and its
+/// lines. Lines start with their origin, i.e. `+`, `-` or a space, like in a patch.
+pub fn hunks_for_file(diff: &Diff, path: &str) -> Result<Vec<(String, Vec<String>)>, git2::Error> {
+    let mut hunks = Vec::new();
+
+    for (index, delta) in diff.deltas().enumerate() {
+        if delta.new_file().path() != Some(Path::new(path)) {
+            continue;
+        }
+
+        let Some(patch) = git2::Patch::from_diff(diff, index)? else {
+            continue;
+        };
+
+        for hunk_index in 0..patch.num_hunks() {
+            let (hunk, num_lines) = patch.hunk(hunk_index)?;
+            let header = String::from_utf8_lossy(hunk.header()).to_string();
+
+            let lines = (0..num_lines)
+                .map(|line_index| {
+                    let line = patch.line_in_hunk(hunk_index, line_index)?;
+                    let content = String::from_utf8_lossy(line.content());
+                    Ok(format!("{}{content}", line.origin()))
+                })
+                .collect::<Result<Vec<_>, git2::Error>>()?;
+
+            hunks.push((header, lines));
+        }
+    }
+
+    Ok(hunks)
+}
+
+/// Describe every hunk in the diff on a single line, in the order they appear in the diff. Deltas
+/// without hunks, like binary files or mode changes, get a single line of their own.
+pub fn hunk_summaries(diff: &Diff) -> Result<Vec<String>, git2::Error> {
+    let mut summaries = Vec::new();
+
+    // Deltas are sorted by path, and hunks_for_file covers all deltas of a path at once
+    let mut last_path_with_hunks = None;
+
+    for (index, delta) in diff.deltas().enumerate() {
+        let path = delta
+            .new_file()
+            .path()
+            .or_else(|| delta.old_file().path())
+            .map(|path| path.display().to_string())
+            .unwrap_or_default();
+
+        let patch = git2::Patch::from_diff(diff, index)?;
+        let num_hunks = patch.as_ref().map_or(0, |patch| patch.num_hunks());
+
+        // Changes without hunks can only be taken as a whole
+        if num_hunks == 0 {
+            let kind = if delta.flags().is_binary() {
+                "binary change"
+            } else {
+                match delta.status() {
+                    Delta::Added => "new file",
+                    Delta::Deleted => "deleted file",
+                    Delta::Renamed => "rename",
+                    Delta::Copied => "copy",
+                    _ => "mode change",
+                }
+            };
+
+            summaries.push(format!("{path} ({kind})"));
+            continue;
+        }
+
+        if last_path_with_hunks.as_ref() == Some(&path) {
+            continue;
+        }
+
+        for (header, _) in hunks_for_file(diff, &path)? {
+            summaries.push(format!("{path} {}", header.trim_end()));
+        }
+        last_path_with_hunks = Some(path);
+    }
+
+    Ok(summaries)
+}
+
+/// Whether each delta of the diff has no hunks, in which case it is selected as a whole
+pub fn hunkless_deltas(diff: &Diff) -> Result<Vec<bool>, git2::Error> {
+    (0..diff.deltas().len())
+        .map(|index| {
+            let patch = git2::Patch::from_diff(diff, index)?;
+            Ok(!matches!(patch, Some(patch) if patch.num_hunks() > 0))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        repo::Repo,
+        testing::{diff_files, two_hunks},
+    };
+
+    #[test]
+    fn pretty_diff_limits_files() {
+        let (repo, _dir) = Repo::temporary();
+        let diff = diff_files(&repo, &[], &[("a", "a\n"), ("b", "b\n"), ("c", "c\n")]);
+
+        let pretty = PrettyDiff::new(&diff, Some(2)).unwrap();
+        assert_eq!(pretty.patches.len(), 2);
+        assert_eq!(pretty.omitted, 1);
+        assert!(pretty.to_string().ends_with("... and 1 more files\n"));
+
+        let pretty = PrettyDiff::new(&diff, None).unwrap();
+        assert_eq!(pretty.patches.len(), 3);
+        assert!(!pretty.to_string().contains("more files"));
+    }
+
+    #[test]
+    fn pretty_patch_shows_submodule_pointers() {
+        let (repo, _dir) = Repo::temporary();
+        let old = repo.commit_files(None, &[("old", "old\n")]).id();
+        let new = repo.commit_files(None, &[("new", "new\n")]).id();
+
+        let gitlink_tree = |pointer| {
+            let mut builder = repo.treebuilder(None).unwrap();
+            builder.insert("sub", pointer, 0o160000).unwrap();
+            repo.find_tree(builder.write().unwrap()).unwrap()
+        };
+        let diff = repo
+            .diff_tree_to_tree(Some(&gitlink_tree(old)), Some(&gitlink_tree(new)), None)
+            .unwrap();
+
+        let mut patch = Patch::from_diff(&diff, 0).unwrap().unwrap();
+        let pretty = PrettyPatch::new(&mut patch).unwrap().to_string();
+        assert!(pretty.starts_with("diff --git a/sub b/sub\n"));
+        assert!(pretty.ends_with(&format!("\nSubproject commit {old}..{new}\n")));
+        assert!(!pretty.contains("@@"));
+        assert!(!pretty.contains("-Subproject"));
+    }
+
+    #[test]
+    fn pretty_patch_shows_renames_once() {
+        let (repo, _dir) = Repo::temporary();
+        let (old, new) = two_hunks();
+        let old = repo.commit_files(None, &[("old", old.as_str())]);
+        let new = repo.commit_files(None, &[("new", new.as_str())]);
+
+        let mut diff = repo.diff_commits(Some(&old), &new, None).unwrap();
+        diff.find_similar(None).unwrap();
+
+        let mut patch = Patch::from_diff(&diff, 0).unwrap().unwrap();
+        let pretty = PrettyPatch::new(&mut patch).unwrap().to_string();
+        assert!(pretty.starts_with(&format!(
+            "{}renamed from a/old to b/new (similarity 90%){}\n",
+            Fg(Cyan),
+            Fg(Reset)
+        )));
+        assert!(!pretty.contains("similarity index"));
+        assert!(!pretty.contains("rename from"));
+        assert!(!pretty.contains("rename to"));
+        assert!(pretty.contains("+first"));
+    }
+
+    #[test]
+    fn hunks_for_file_takes_only_that_file() {
+        let (repo, _dir) = Repo::temporary();
+        let (old, new) = two_hunks();
+        let diff = diff_files(
+            &repo,
+            &[("a", old.as_str()), ("b", "b\n")],
+            &[("a", new.as_str()), ("b", "c\n")],
+        );
+
+        let hunks = hunks_for_file(&diff, "a").unwrap();
+        assert_eq!(hunks.len(), 2);
+        assert!(hunks[0].0.starts_with("@@ -1,"));
+        assert!(hunks[0].1.contains(&"-1\n".to_string()));
+        assert!(hunks[0].1.contains(&"+first\n".to_string()));
+
+        let hunks = hunks_for_file(&diff, "b").unwrap();
+        assert_eq!(hunks.len(), 1);
+        assert_eq!(hunks[0].1, ["-b\n", "+c\n"]);
+
+        assert!(hunks_for_file(&diff, "missing").unwrap().is_empty());
+    }
+
+    #[test]
+    fn hunk_summaries_include_hunkless_changes() {
+        let (repo, _dir) = Repo::temporary();
+        let (old, new) = two_hunks();
+        let diff = diff_files(
+            &repo,
+            &[("a", old.as_str())],
+            &[("a", new.as_str()), ("empty", ""), ("binary", "\0\x01")],
+        );
+
+        let summaries = hunk_summaries(&diff).unwrap();
+        assert_eq!(summaries.len(), 4);
+        assert!(summaries[0].starts_with("a @@ -1,"));
+        assert!(summaries[1].starts_with("a @@ -17,"));
+        assert_eq!(summaries[2], "binary (binary change)");
+        assert_eq!(summaries[3], "empty (new file)");
+
+        assert_eq!(hunkless_deltas(&diff).unwrap(), [false, true, true]);
+    }
+}
@@ -2,13 +2,21 @@ mod commit;
 mod diffs;
 mod repo;
 mod state;
+#[cfg(test)]
+mod testing;
 
-use crate::state::{MoveResult, State};
+use crate::state::{MoveResult, Porcelain, State, Steps};
 use clap::{Parser, Subcommand};
+use commit::{MergeTool, StrategyOption, Trailer};
 use diffs::PrettyDiff;
-use repo::Repo;
-use state::Manager;
-use std::error::Error;
+use repo::{Repo, SignatureStatus};
+use state::{CommitDate, CommitInfo, CommitInfoField, Manager, PlainSig};
+use std::{
+    error::Error,
+    io::{self, Write},
+    path::PathBuf,
+    process,
+};
 
 #[derive(Parser, Debug)]
 #[command()]
@@ -17,6 +25,14 @@ struct Args {
     #[arg(long, default_value = ".")]
     repo: String,
 
+    /// Suppress informational notices
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// Save the navigation state even if another invocation changed it in the meantime
+    #[arg(long, global = true)]
+    force_state: bool,
+
     #[command(subcommand)]
     command: Cmd,
 }
@@ -52,15 +68,79 @@ enum Cmd {
         /// Forcefully resolve conflicts that may arise during cherry-picking
         #[arg(short, long)]
         forceful: bool,
+
+        /// Print the ID of every intermediate commit, not just the final one
+        #[arg(long)]
+        print_all: bool,
+
+        /// Pass a merge strategy option, e.g. "ignore-space-change" or "diff-algorithm=patience"
+        #[arg(short = 'X', long = "strategy-option")]
+        strategy_options: Vec<StrategyOption>,
+
+        /// Use this committer, given as "Name <email>", instead of the original ones
+        #[arg(long)]
+        committer: Option<PlainSig>,
+
+        /// Resolve conflicts with the merge tool configured for `git mergetool`
+        #[arg(long, conflicts_with = "forceful")]
+        mergetool: bool,
     },
 
     /// Move to next commit
     #[command(visible_alias = "n")]
-    Next {},
+    Next {
+        /// Number of commits to move by
+        #[arg(default_value_t = 1)]
+        steps: usize,
+
+        /// Leave paths matching this glob untouched in the working directory
+        #[arg(long)]
+        preserve: Vec<String>,
+
+        /// Show the changes between the previous and the new commit
+        #[arg(long)]
+        show_diff: bool,
+
+        /// Only check whether the next commit applies cleanly, without moving
+        #[arg(long)]
+        check: bool,
+
+        /// Resolve conflicts with the merge tool configured for `git mergetool`
+        #[arg(long, conflicts_with = "check")]
+        mergetool: bool,
+    },
+
+    /// Move to the last commit in the queue
+    #[command()]
+    Tip {
+        /// Leave paths matching this glob untouched in the working directory
+        #[arg(long)]
+        preserve: Vec<String>,
+    },
+
+    /// Move to the commit the stack is built on, the latest pushed commit or the root commit
+    #[command()]
+    Base {
+        /// Leave paths matching this glob untouched in the working directory
+        #[arg(long)]
+        preserve: Vec<String>,
+    },
 
     /// Move to previous commit
     #[command(visible_alias = "p")]
-    Prev {},
+    Prev {
+        /// Number of commits to move by
+        #[arg(default_value_t = 1)]
+        steps: usize,
+
+        /// Leave paths matching this glob untouched in the working directory
+        #[arg(long)]
+        preserve: Vec<String>,
+
+        /// Show the changes between the previous and the new commit
+        #[arg(long)]
+        show_diff: bool,
+    },
 
     /// Produce a new commit with the staged changes
     #[command(visible_alias = "co")]
@@ -69,9 +149,53 @@ enum Cmd {
         #[arg(short, long)]
         msg: Option<String>,
 
+        /// Pre-fill the commit message with this template instead of `commit.template`
+        #[arg(short, long)]
+        template: Option<PathBuf>,
+
         /// Only commit changes in the index
         #[arg(short = 'i', long = "index")]
         use_index: bool,
+
+        /// Show entire files instead of hunks in the commit message editor
+        #[arg(long)]
+        full_context: bool,
+
+        /// Author date: "now", "@<unix> [+-HHMM]" or an RFC 3339 date
+        #[arg(long)]
+        date: Option<CommitDate>,
+
+        /// Add a trailer to the commit message, e.g. "Signed-off-by: Name <email>"
+        #[arg(long = "trailer")]
+        trailers: Vec<Trailer>,
+
+        /// Use this committer, given as "Name <email>"
+        #[arg(long)]
+        committer: Option<PlainSig>,
+
+        /// Create the commit even if there are no changes
+        #[arg(long)]
+        allow_empty: bool,
+    },
+
+    /// Cherry-pick a commit on top of the active commit
+    #[command(visible_alias = "cp")]
+    CherryPick {
+        /// Commit to pick
+        #[arg()]
+        rev: String,
+
+        /// Sign the resulting commit
+        #[arg(short, long)]
+        sign: bool,
+    },
+
+    /// Apply a patch file as a new commit on top of the active commit
+    #[command()]
+    Apply {
+        /// Patch file in unified diff format
+        #[arg()]
+        patch_file: PathBuf,
     },
 
     /// Incorporate the staged changes into the active commit
@@ -80,6 +204,22 @@ enum Cmd {
         /// Only amend with changes in the index
         #[arg(short = 'i', long = "index")]
         use_index: bool,
+
+        /// Select the hunks to incorporate in an editor
+        #[arg(short, long, conflicts_with_all = ["author", "committer"])]
+        patch: bool,
+
+        /// Amend even if the commit has already been pushed
+        #[arg(long)]
+        force: bool,
+
+        /// Only replace the author, given as "Name <email>"
+        #[arg(long, conflicts_with = "use_index")]
+        author: Option<PlainSig>,
+
+        /// Only replace the committer, given as "Name <email>"
+        #[arg(long, conflicts_with = "use_index")]
+        committer: Option<PlainSig>,
     },
 
     /// Edit commit meta data
@@ -99,11 +239,43 @@ enum Cmd {
 
         #[arg(short, long)]
         message: Option<String>,
+
+        /// Pick the field to edit from a menu instead of editing all of them as JSON
+        #[arg(short, long)]
+        interactive: bool,
     },
 
     /// Edit commit message
     #[command(visible_alias = "em")]
-    EditMessage {},
+    EditMessage {
+        /// Commit to reword, HEAD or one of its ancestors; the commits above it are recreated
+        #[arg(default_value = "HEAD")]
+        commit_ref: String,
+
+        /// Reword even if the commit has been pushed or signed commits would lose their signature
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Create a worktree to work on a commit in parallel
+    #[command()]
+    Worktree {
+        /// Location of the new worktree, its name is also used for the new branch
+        #[arg()]
+        path: PathBuf,
+
+        /// Commit to check out in the worktree
+        #[arg(default_value = "HEAD")]
+        commit_ref: String,
+    },
+
+    /// Verify the signatures of a commit or a range of commits
+    #[command()]
+    Verify {
+        /// Commit or range of commits, e.g. "main..HEAD"
+        #[arg(default_value = "HEAD")]
+        revspec: String,
+    },
 
     /// Display the staged changes
     #[command()]
@@ -111,16 +283,74 @@ enum Cmd {
         /// Onlys show changes in the index
         #[arg(short = 'i', long = "index")]
         use_index: bool,
+
+        /// Render at most this many files
+        #[arg(long)]
+        max_files: Option<usize>,
     },
 
-    /// Display the staged changes
+    /// Display a commit and its changes
+    #[command()]
+    Show {
+        /// Commit to show
+        #[arg(default_value = "HEAD")]
+        commit_ref: String,
+
+        /// Render at most this many files
+        #[arg(long)]
+        max_files: Option<usize>,
+    },
+
+    /// Display the active commit
     #[command(visible_alias = "i")]
-    Info {},
+    Info {
+        /// Print stable, tab-separated output without colours
+        #[arg(long)]
+        porcelain: bool,
+
+        /// Print one line per commit, including the queued ones, like `git log --oneline`
+        #[arg(long, conflicts_with = "porcelain")]
+        oneline: bool,
+    },
+
+    /// List stacks, or create and switch between them
+    #[command(visible_alias = "stacks")]
+    Stack {
+        #[command(subcommand)]
+        command: Option<StackCmd>,
+    },
+
+    /// Display the history of navigation operations
+    #[command()]
+    StateLog {},
+
+    /// List commits left behind by interrupted operations
+    #[command()]
+    Recover {},
 
     ///
     Test {},
 }
 
+#[derive(Subcommand, Debug)]
+enum StackCmd {
+    /// Create a new stack starting at the active commit and switch to it
+    New {
+        /// Name of the stack
+        #[arg()]
+        name: String,
+    },
+
+    /// Switch to another stack, checking out the commit that was active there
+    Switch {
+        /// Name of the stack
+        #[arg()]
+        name: String,
+    },
+}
+
+/// Cherry-pick the added refs on top of the base, returning the commits to print: every commit
+/// that was created with `print_all`, or only the last one otherwise.
 #[allow(clippy::too_many_arguments)]
 fn chain(
     repo: &Repo,
@@ -131,7 +361,11 @@ fn chain(
     update_ref: Option<String>,
     push: Option<String>,
     forceful: bool,
-) -> Result<(), Box<dyn Error>> {
+    print_all: bool,
+    strategy_options: Vec<StrategyOption>,
+    mergetool: Option<MergeTool>,
+    committer: Option<git2::Signature>,
+) -> Result<Vec<git2::Oid>, Box<dyn Error>> {
     let mut commit = repo.find_commit(base_ref)?;
     let num_refs = added_refs.len();
     let add_commits = added_refs
@@ -146,8 +380,22 @@ fn chain(
         commit = repo.merge_base(&all_commits)?;
     }
 
+    let mut printed = Vec::new();
+
     for new_commit in add_commits {
-        commit = commit.cherry_pick(repo, &new_commit, sign, forceful)?;
+        commit = commit.cherry_pick(
+            repo,
+            &new_commit,
+            sign,
+            forceful,
+            &strategy_options,
+            mergetool.as_ref(),
+            committer.as_ref(),
+        )?;
+
+        if print_all {
+            printed.push(commit.id());
+        }
     }
 
     if let Some(ref_) = update_ref {
@@ -158,15 +406,158 @@ fn chain(
         }
     }
 
-    println!("{}", commit.id());
+    if !print_all || num_refs == 0 {
+        printed.push(commit.id());
+    }
+
+    Ok(printed)
+}
+
+fn prompt(question: impl AsRef<str>) -> Result<String, Box<dyn Error>> {
+    eprint!("{}", question.as_ref());
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+
+    Ok(answer.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn edit_interactively(mgr: &Manager, info: &mut CommitInfo) -> Result<(), Box<dyn Error>> {
+    for (index, field) in CommitInfoField::ALL.iter().enumerate() {
+        eprintln!("{}) {}", index + 1, field.name());
+    }
+
+    let field = loop {
+        let choice = prompt("Which field do you want to edit? ")?;
+
+        match CommitInfoField::select(&choice) {
+            Some(field) => break field,
+            None => eprintln!("Unknown field {choice:?}"),
+        }
+    };
+
+    let value = match field {
+        CommitInfoField::Message => {
+            mgr.compose_commit_message(Some(field.get(info).clone()), None)?
+        }
+
+        _ => {
+            let value = prompt(format!("New {} [{}]: ", field.name(), field.get(info)))?;
+
+            if value.is_empty() {
+                field.get(info).clone()
+            } else {
+                value
+            }
+        }
+    };
+
+    *field.get_mut(info) = value;
+
+    Ok(())
+}
+
+fn report_carried(repo: &Repo, carried: (usize, usize)) -> Result<(), Box<dyn Error>> {
+    let (staged, unstaged) = carried;
+
+    if staged > 0 || unstaged > 0 {
+        let target = repo.head_commit()?.id();
+        eprintln!("carrying {staged} staged, {unstaged} unstaged changes to {target}");
+    }
+
+    Ok(())
+}
+
+/// Move through the stack with `step`, which returns the outcome along with the number of steps
+/// that were asked for, and report where it ended up.
+fn navigate(
+    mgr: &Manager,
+    quiet: bool,
+    preserve: Vec<String>,
+    show_diff: bool,
+    step: impl FnOnce(&mut State, &[String]) -> Result<(Steps, usize), state::Error>,
+) -> Result<(), Box<dyn Error>> {
+    mgr.repo().ensure_no_operation_in_progress()?;
+    let carried = mgr.repo().uncommitted_changes()?;
+    let mut state = State::read(mgr)?.validate(mgr)?;
+    let preserve = mgr.preserve_paths(preserve)?;
+
+    let (
+        Steps {
+            result,
+            taken,
+            interrupted,
+        },
+        steps,
+    ) = step(&mut state, &preserve)?;
+    eprintln!("{result}");
+
+    if steps > 1 && taken < steps {
+        match interrupted {
+            Some(err) => eprintln!("stopped after {taken} of {steps} steps: {err}"),
+            None => eprintln!("stopped after {taken} of {steps} steps"),
+        }
+    }
+
+    eprintln!("{}", mgr.repo().head_state()?);
+
+    if !quiet && matches!(result, MoveResult::Moved { .. }) {
+        report_carried(mgr.repo(), carried)?;
+    }
+
+    if show_diff {
+        print_move_diff(mgr.repo(), &result)?;
+    }
+
+    Ok(())
+}
+
+fn print_move_diff(repo: &Repo, result: &MoveResult) -> Result<(), Box<dyn Error>> {
+    if let MoveResult::Moved { from, to, .. } = result {
+        let from = repo.find_commit(from.to_string())?;
+        let to = repo.find_commit(to.to_string())?;
+        let mut diff = repo.diff_commits(Some(&from), &to, None)?;
+        diff.find_similar(None)?;
+
+        println!("{}", PrettyDiff::new(&diff, None)?);
+    }
+
+    Ok(())
+}
+
+fn print_oneline(repo: &Repo, oid: git2::Oid, active: bool) -> Result<(), Box<dyn Error>> {
+    let commit = repo.find_commit(oid.to_string())?;
+    let short_id = commit.as_object().short_id()?;
+    let marker = if active { '*' } else { ' ' };
+
+    println!(
+        "{marker} {} {}",
+        short_id.as_str().unwrap_or(""),
+        commit.summary().unwrap_or("")
+    );
 
     Ok(())
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
+
+    match run(args) {
+        // The user cancelled the operation in their editor, there's nothing to report.
+        Err(err) if matches!(err.downcast_ref(), Some(state::Error::EditorAborted)) => {
+            eprintln!("aborted");
+            process::exit(1);
+        }
+
+        result => result,
+    }
+}
+
+fn run(args: Args) -> Result<(), Box<dyn Error>> {
     let repo = Repo::discover(args.repo.as_str())?;
-    let mgr = Manager::new(repo);
+    let mut mgr = Manager::new(repo);
+    mgr.set_force_state(args.force_state);
 
     match args.command {
         Cmd::Chain {
@@ -177,47 +568,168 @@ fn main() -> Result<(), Box<dyn Error>> {
             update_ref,
             push,
             forceful,
-        } => chain(
-            mgr.repo(),
-            base_ref,
-            use_merge_base,
-            added_refs,
-            sign,
-            update_ref,
-            push,
-            forceful,
-        )?,
+            print_all,
+            strategy_options,
+            committer,
+            mergetool,
+        } => {
+            let printed = chain(
+                mgr.repo(),
+                base_ref,
+                use_merge_base,
+                added_refs,
+                sign,
+                update_ref,
+                push,
+                forceful,
+                print_all,
+                strategy_options,
+                mergetool
+                    .then(|| MergeTool::from_config(&mgr.repo().config()?))
+                    .transpose()?,
+                mgr.committer_override(committer)?,
+            )?;
 
-        Cmd::Next {} => {
-            let mut state = State::read(&mgr)?.validate(&mgr)?;
-            let result = state.next(&mgr)?;
+            for oid in printed {
+                println!("{oid}");
+            }
+        }
+
+        Cmd::Next { check: true, .. } => {
+            let state = State::read(&mgr)?.validate(&mgr)?;
+            let result = state.check_next(&mgr)?;
             eprintln!("{result}");
         }
 
-        Cmd::Prev {} => {
+        Cmd::Next {
+            steps,
+            preserve,
+            show_diff,
+            mergetool,
+            ..
+        } => {
+            let mergetool = mergetool
+                .then(|| MergeTool::from_config(&mgr.repo().config()?))
+                .transpose()?;
+            navigate(
+                &mgr,
+                args.quiet,
+                preserve,
+                mgr.show_diff(show_diff)?,
+                |state, preserve| {
+                    Ok((
+                        state.next(&mgr, preserve, mergetool.as_ref(), steps)?,
+                        steps,
+                    ))
+                },
+            )?
+        }
+
+        Cmd::Tip { preserve } => navigate(&mgr, args.quiet, preserve, false, |state, preserve| {
+            let steps = state.depth();
+            Ok((state.next(&mgr, preserve, None, steps)?, steps))
+        })?,
+
+        Cmd::Base { preserve } => {
+            navigate(&mgr, args.quiet, preserve, false, |state, preserve| {
+                let steps = mgr.stack_height()?;
+                Ok((state.prev(&mgr, preserve, steps)?, steps))
+            })?
+        }
+
+        Cmd::Prev {
+            steps,
+            preserve,
+            show_diff,
+        } => navigate(
+            &mgr,
+            args.quiet,
+            preserve,
+            mgr.show_diff(show_diff)?,
+            |state, preserve| Ok((state.prev(&mgr, preserve, steps)?, steps)),
+        )?,
+
+        Cmd::Commit {
+            msg,
+            template,
+            use_index,
+            full_context,
+            date,
+            trailers,
+            committer,
+            allow_empty,
+        } => {
+            mgr.repo().ensure_no_operation_in_progress()?;
             let mut state = State::read(&mgr)?.validate(&mgr)?;
-            let result = state.prev(&mgr)?;
+            let result = state.commit(
+                &mgr,
+                msg,
+                template.as_deref(),
+                use_index,
+                full_context,
+                date,
+                &trailers,
+                committer,
+                allow_empty,
+            )?;
             eprintln!("{result}");
         }
 
-        Cmd::Commit { msg, use_index } => {
+        Cmd::CherryPick { rev, sign } => {
+            mgr.repo().ensure_no_operation_in_progress()?;
             let mut state = State::read(&mgr)?.validate(&mgr)?;
-            let result = state.commit(&mgr, msg, use_index)?;
+            let result = state.cherry_pick(&mgr, &rev, sign)?;
             eprintln!("{result}");
         }
 
-        Cmd::Amend { use_index } => {
+        Cmd::Apply { patch_file } => {
+            mgr.repo().ensure_no_operation_in_progress()?;
             let mut state = State::read(&mgr)?.validate(&mgr)?;
-            let result = state.amend(&mgr, use_index)?;
+            let result = state.apply(&mgr, &patch_file)?;
             eprintln!("{result}");
         }
 
+        Cmd::Amend {
+            use_index,
+            patch,
+            author,
+            committer,
+            force,
+        } => {
+            mgr.repo().ensure_no_operation_in_progress()?;
+
+            if !force {
+                mgr.repo()
+                    .ensure_not_pushed(mgr.repo().head_commit()?.id())?;
+            }
+
+            if author.is_some() || committer.is_some() {
+                let mut info = mgr.commit_info()?;
+
+                if let Some(author) = author {
+                    info.author = author;
+                }
+
+                if let Some(committer) = committer {
+                    info.committer = committer;
+                }
+
+                let result = mgr.edit(&info)?;
+                eprintln!("{result}");
+            } else {
+                let mut state = State::read(&mgr)?.validate(&mgr)?;
+                let result = state.amend(&mgr, use_index, patch)?;
+                eprintln!("{result}");
+            }
+        }
+
         Cmd::Edit {
             author_name,
             author_email,
             committer_name,
             committer_email,
             message,
+            interactive,
         } => {
             let mut info = mgr.commit_info()?;
 
@@ -247,7 +759,9 @@ fn main() -> Result<(), Box<dyn Error>> {
                 info.message = message;
             }
 
-            if need_edit {
+            if need_edit && interactive {
+                edit_interactively(&mgr, &mut info)?;
+            } else if need_edit {
                 let info_rendered = serde_json::ser::to_string_pretty(&info)?;
                 let info_edited =
                     mgr.compose_message_plain(&mgr.commit_info_file(), info_rendered)?;
@@ -258,39 +772,219 @@ fn main() -> Result<(), Box<dyn Error>> {
             eprintln!("{result}");
         }
 
-        Cmd::EditMessage {} => {
-            let mut info = mgr.commit_info()?;
-            info.message = mgr.compose_commit_message(Some(info.message), None)?;
+        Cmd::EditMessage { commit_ref, force } => {
+            let target = mgr.repo().find_commit(commit_ref)?;
 
-            let result = mgr.edit(&info)?;
+            // Check before the editor is opened, so that the new message is not lost
+            mgr.check_reword(&target, force)?;
+
+            let message = target.message().unwrap_or("").to_string();
+            let message = mgr.compose_commit_message(Some(message), None)?;
+
+            let result = mgr.reword_commit(&target, &message, force)?;
             eprintln!("{result}");
         }
 
-        Cmd::Staged { use_index } => {
+        Cmd::Worktree { path, commit_ref } => {
+            let commit = mgr.repo().find_commit(commit_ref)?;
+            mgr.repo().create_worktree(&path, &commit)?;
+            println!("{}", commit.id());
+        }
+
+        Cmd::Verify { revspec } => {
+            let repo = mgr.repo();
+
+            let oids = if revspec.contains("..") {
+                repo.commits_in_range(revspec.as_str())?
+            } else {
+                vec![repo.find_commit(revspec)?.id()]
+            };
+
+            let mut all_good = true;
+            for oid in oids {
+                let status = repo.verify_commit(oid)?;
+                all_good &= matches!(status, SignatureStatus::Good { .. });
+
+                let commit = repo.0.find_commit(oid)?;
+                println!("{oid} {status} {}", commit.summary().unwrap_or(""));
+            }
+
+            if !all_good {
+                process::exit(1);
+            }
+        }
+
+        Cmd::Staged {
+            use_index,
+            max_files,
+        } => {
             let tree = mgr.capture_tree(use_index)?;
-            let diff = mgr.repo().diff_tree_to_tree(
+            let mut diff = mgr.repo().diff_tree_to_tree(
                 Some(&mgr.repo().head_commit()?.tree()?),
                 Some(&tree),
                 None,
             )?;
+            diff.find_similar(None)?;
 
-            let pretty = PrettyDiff::new(&diff)?;
+            let pretty = PrettyDiff::new(&diff, max_files)?;
             println!("{pretty}");
         }
 
-        Cmd::Info {} => {
-            println!(
-                "{}",
-                MoveResult::stationary(mgr.repo().head_commit()?.as_ref())
-            )
+        Cmd::Show {
+            commit_ref,
+            max_files,
+        } => {
+            let commit = mgr.repo().find_commit(commit_ref)?;
+            // A root commit is diffed against the empty tree
+            let parent = match commit.parent_count() {
+                0 => None,
+                _ => Some(commit.parent(0)?.into()),
+            };
+            let mut diff = mgr.repo().diff_commits(parent.as_ref(), &commit, None)?;
+            diff.find_similar(None)?;
+
+            println!("{}", MoveResult::stationary(commit.as_ref()));
+            println!();
+
+            let pretty = PrettyDiff::new(&diff, max_files)?;
+            println!("{pretty}");
         }
 
-        Cmd::Test {} => {
+        Cmd::Info { porcelain, oneline } => {
             let state = State::read(&mgr)?.validate(&mgr)?;
-            state.write(&mgr)?;
+            let head = mgr.repo().head_commit()?;
+            let tags = mgr.repo().tags_for_commit(head.id())?;
+            let result = MoveResult::stationary(head.as_ref());
+
+            if oneline {
+                // Queued commits come after the active one, so they are listed first like newer
+                // commits in `git log`.
+                for oid in state.queued().into_iter().rev() {
+                    print_oneline(mgr.repo(), oid, false)?;
+                }
+                print_oneline(mgr.repo(), head.id(), true)?;
+            } else if porcelain {
+                print!("{}", Porcelain(&result));
+                for tag in tags.iter() {
+                    println!("tag\t{tag}");
+                }
+                for (key, value) in head.message_trailers()? {
+                    println!("trailer\t{key}\t{value}");
+                }
+                println!("queue\t{}", state.depth());
+            } else {
+                println!("{result}");
+
+                if !tags.is_empty() {
+                    println!("tags    {}", tags.join(", "));
+                }
+
+                if !state.is_at_end() {
+                    println!("{} commits ahead in queue", state.depth());
+                }
+            }
+        }
+
+        Cmd::Stack { command: None } => {
+            let active = mgr.active_stack()?;
+
+            for stack in mgr.stacks()? {
+                let marker = if stack == active { '*' } else { ' ' };
+                println!("{marker} {stack}");
+            }
+        }
+
+        Cmd::Stack {
+            command: Some(command),
+        } => {
+            mgr.repo().ensure_no_operation_in_progress()?;
+
+            let result = match command {
+                StackCmd::New { name } => mgr.switch_stack(&name, true)?,
+                StackCmd::Switch { name } => mgr.switch_stack(&name, false)?,
+            };
+            eprintln!("{result}");
+        }
+
+        Cmd::StateLog {} => {
+            print!("{}", State::read_log(&mgr)?);
+        }
+
+        Cmd::Recover {} => {
+            for (name, oid) in mgr.wip_refs()? {
+                let commit = mgr.repo().find_commit(oid.to_string())?;
+                println!("{name} {oid} {}", commit.summary().unwrap_or(""));
+            }
+        }
+
+        Cmd::Test {} => {
+            let mut state = State::read(&mgr)?.validate(&mgr)?;
+            let head = mgr.repo().head_commit()?.id();
+            state.write(&mgr, "test", head, head)?;
             eprintln!("{state:#?}");
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain_refs(repo: &Repo, print_all: bool) -> Vec<git2::Oid> {
+        let base = repo.commit_files(None, &[("base", "base\n")]);
+        let added_refs = ["a", "b", "c"]
+            .iter()
+            .map(|file| {
+                repo.commit_files(Some(&base), &[(file, "added\n")])
+                    .id()
+                    .to_string()
+            })
+            .collect();
+
+        chain(
+            repo,
+            base.id().to_string(),
+            false,
+            added_refs,
+            false,
+            None,
+            None,
+            false,
+            print_all,
+            Vec::new(),
+            None,
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn chain_prints_all_intermediate_commits() {
+        let (repo, _dir) = Repo::temporary();
+        let printed = chain_refs(&repo, true);
+        assert_eq!(printed.len(), 3);
+
+        for (oid, parent) in printed.iter().skip(1).zip(printed.iter()) {
+            let commit = repo.find_commit(oid.to_string()).unwrap();
+            assert_eq!(commit.parent_id(0).unwrap(), *parent);
+        }
+    }
+
+    #[test]
+    fn chain_prints_only_the_last_commit() {
+        let (repo, _dir) = Repo::temporary();
+        let printed = chain_refs(&repo, false);
+        assert_eq!(printed.len(), 1);
+
+        let tree = repo
+            .find_commit(printed[0].to_string())
+            .unwrap()
+            .tree()
+            .unwrap();
+        for file in ["base", "a", "b", "c"] {
+            assert!(tree.get_name(file).is_some(), "{file} is missing");
+        }
+    }
+}
@@ -1,7 +1,10 @@
-use crate::commit::Commit;
+use crate::{
+    commit::{self, Commit, Trailer},
+    diffs,
+};
 use auth_git2::GitAuthenticator;
 use git2::{Diff, Oid, ResetType};
-use std::{path::Path, str::Utf8Error};
+use std::{cell::Cell, path::Path, str::Utf8Error};
 
 #[derive(Debug, derive_more::Display, derive_more::From, derive_more::Error)]
 pub enum Error {
@@ -12,6 +15,98 @@ pub enum Error {
     IndexConflicts,
     WorkingDirConflicts,
     Conflicts,
+    NoWorkingDir,
+
+    #[display(fmt = "A rebase is in progress, finish or abort it first")]
+    RebaseInProgress,
+
+    #[display(fmt = "A cherry-pick is in progress, finish or abort it first")]
+    CherryPickInProgress,
+
+    #[display(fmt = "Signature format {format} is not supported")]
+    UnsupportedSignatureFormat {
+        format: String,
+    },
+
+    #[display(fmt = "Commit {commit} has already been pushed, use --force to rewrite it anyway")]
+    AlreadyPushed {
+        commit: Oid,
+    },
+
+    #[display(fmt = "Hunk selection has {selected} entries, but the diff has {hunks} hunks")]
+    HunkSelectionMismatch {
+        selected: usize,
+        hunks: usize,
+    },
+}
+
+/// Change of a reference made by [Repo::update_references_batch]
+#[derive(Debug, Clone)]
+pub struct RefUpdate {
+    name: String,
+    target: Oid,
+
+    /// Target the reference must still have for the batch to go ahead, `Some(None)` if it must not
+    /// exist yet
+    expected: Option<Option<Oid>>,
+
+    /// Message of the entry to add to the reflog of the reference
+    log_message: Option<String>,
+}
+
+impl RefUpdate {
+    pub fn set(name: impl Into<String>, target: Oid) -> Self {
+        Self {
+            name: name.into(),
+            target,
+            expected: None,
+            log_message: None,
+        }
+    }
+
+    /// Refuse the update unless the reference still points to `expected`, or doesn't exist if it is
+    /// `None`
+    pub fn expecting(mut self, expected: Option<Oid>) -> Self {
+        self.expected = Some(expected);
+        self
+    }
+
+    /// Record the update in the reflog of the reference, whether or not git would keep a reflog for
+    /// it by itself
+    pub fn logged(mut self, message: impl Into<String>) -> Self {
+        self.log_message = Some(message.into());
+        self
+    }
+}
+
+impl From<(String, Oid)> for RefUpdate {
+    fn from((name, target): (String, Oid)) -> Self {
+        Self::set(name, target)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, derive_more::Display)]
+pub enum SignatureStatus {
+    #[display(fmt = "unsigned")]
+    Unsigned,
+
+    #[display(fmt = "good signature by {key}")]
+    Good { key: String },
+
+    #[display(fmt = "BAD signature by {key}")]
+    Bad { key: String },
+
+    #[display(fmt = "cannot verify signature by {key}, public key not available")]
+    UnknownKey { key: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, derive_more::Display)]
+pub enum HeadState {
+    #[display(fmt = "detached at {_0}")]
+    Detached(Oid),
+
+    #[display(fmt = "on branch {_0}")]
+    Branch(String),
 }
 
 #[derive(
@@ -32,25 +127,57 @@ impl Repo {
             .expect("Could not initialise temporary repository");
         let repo = Repo(repo);
 
+        let mut config = repo.0.config().expect("Could not open config");
+        config
+            .set_str("user.name", "Test")
+            .expect("Could not set user name");
+        config
+            .set_str("user.email", "test@example.com")
+            .expect("Could not set user email");
+
         (repo, temp_dir)
     }
 
     pub fn find_commit(&self, ref_: impl AsRef<str>) -> Result<Commit<'_>, git2::Error> {
-        let commit = self
-            .0
-            .revparse(ref_.as_ref())?
-            .from()
-            .expect("Bad commit")
-            .peel_to_commit()?;
+        let commit = self.0.revparse_single(ref_.as_ref())?.peel_to_commit()?;
 
         Ok(Commit(commit))
     }
 
+    /// Look up a reference, distinguishing a missing reference from other errors
+    pub fn try_find_reference(
+        &self,
+        name: &str,
+    ) -> Result<Option<git2::Reference<'_>>, git2::Error> {
+        match self.0.find_reference(name) {
+            Ok(reference) => Ok(Some(reference)),
+            Err(err) if err.code() == git2::ErrorCode::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn ref_exists(&self, name: &str) -> Result<bool, git2::Error> {
+        Ok(self.try_find_reference(name)?.is_some())
+    }
+
     pub fn head_commit(&self) -> Result<Commit, git2::Error> {
         let commit = self.0.head()?.peel_to_commit()?;
         Ok(Commit(commit))
     }
 
+    pub fn head_state(&self) -> Result<HeadState, git2::Error> {
+        let head = self.0.head()?;
+
+        if self.0.head_detached()? {
+            let oid = head.peel_to_commit()?.id();
+            Ok(HeadState::Detached(oid))
+        } else {
+            Ok(HeadState::Branch(
+                head.shorthand().unwrap_or("HEAD").to_string(),
+            ))
+        }
+    }
+
     pub fn commit_signed<'a, 'b>(
         &'a self,
         author: &git2::Signature,
@@ -86,6 +213,70 @@ impl Repo {
         Ok(Commit(new_commit))
     }
 
+    pub fn verify_commit(&self, oid: Oid) -> Result<SignatureStatus, Error> {
+        // Signing only ever uses OpenPGP, so that is all we can verify as well.
+        match self.0.config()?.get_string("gpg.format") {
+            Ok(format) if format != "openpgp" => {
+                return Err(Error::UnsupportedSignatureFormat { format })
+            }
+            Ok(_) => {}
+            Err(err) if err.code() == git2::ErrorCode::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        let (signature, signed_data) = match self.0.extract_signature(&oid, None) {
+            Ok(extracted) => extracted,
+            Err(err) if err.code() == git2::ErrorCode::NotFound => {
+                return Ok(SignatureStatus::Unsigned)
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut ctx = gpgme::Context::from_protocol(gpgme::Protocol::OpenPgp)?;
+        let result = ctx.verify_detached(&*signature, &*signed_data)?;
+
+        let status = match result.signatures().next() {
+            Some(sig) => {
+                let key = sig.fingerprint().unwrap_or("unknown").to_string();
+                match sig.status() {
+                    Ok(()) => SignatureStatus::Good { key },
+                    Err(_) if sig.summary().contains(gpgme::SignatureSummary::KEY_MISSING) => {
+                        SignatureStatus::UnknownKey { key }
+                    }
+                    Err(_) => SignatureStatus::Bad { key },
+                }
+            }
+
+            None => SignatureStatus::Bad {
+                key: "unknown".to_string(),
+            },
+        };
+
+        Ok(status)
+    }
+
+    /// Commits in a range like `A..B` or `A...B`, parents first
+    pub fn commits_in_range(&self, range: &str) -> Result<Vec<Oid>, git2::Error> {
+        let spec = self.0.revparse(range)?;
+        let (Some(from), Some(to)) = (spec.from(), spec.to()) else {
+            return Err(git2::Error::from_str("Expected a range like A..B or A...B"));
+        };
+
+        let mut revwalk = self.0.revwalk()?;
+        revwalk.push(to.id())?;
+
+        if spec.mode().contains(git2::RevparseMode::MERGE_BASE) {
+            // A...B contains the commits reachable from either side, but not from both
+            revwalk.push(from.id())?;
+            revwalk.hide(self.0.merge_base(from.id(), to.id())?)?;
+        } else {
+            revwalk.hide(from.id())?;
+        }
+
+        revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+        revwalk.collect()
+    }
+
     pub fn commit<'a, 'b>(
         &'a self,
         author: &git2::Signature,
@@ -110,6 +301,23 @@ impl Repo {
         Ok(Commit(new_commit))
     }
 
+    #[allow(clippy::too_many_arguments)]
+    pub fn commit_with_trailers<'a, 'b>(
+        &'a self,
+        author: &git2::Signature,
+        committer: &git2::Signature,
+        message: impl AsRef<str>,
+        trailers: &[Trailer],
+        tree: &git2::Tree,
+        parents: impl IntoIterator<Item = &'b Commit<'a>>,
+    ) -> Result<Commit<'a>, Error>
+    where
+        'a: 'b,
+    {
+        let message = commit::append_trailers(message.as_ref(), trailers);
+        self.commit(author, committer, message, tree, parents)
+    }
+
     pub fn merge<'a>(
         &'a self,
         first: &Commit<'a>,
@@ -136,6 +344,72 @@ impl Repo {
         Ok(ref_)
     }
 
+    /// Identity for reflog entries, which unlike commits don't need one to be configured
+    fn reflog_signature(&self) -> Result<git2::Signature<'static>, git2::Error> {
+        self.0
+            .signature()
+            .or_else(|_| git2::Signature::now("unstacked", "unstacked"))
+    }
+
+    /// Update several references at once, so that either all of them or none are updated
+    pub fn update_references_batch(
+        &self,
+        updates: impl IntoIterator<Item = impl Into<RefUpdate>>,
+    ) -> Result<(), git2::Error> {
+        let updates = updates.into_iter().map(Into::into).collect::<Vec<_>>();
+        let mut transaction = self.0.transaction()?;
+
+        for update in updates.iter() {
+            transaction.lock_ref(&update.name)?;
+        }
+
+        // The references are locked, no one else can change them between checking and updating
+        for update in updates.iter() {
+            if let Some(expected) = update.expected {
+                let current = self
+                    .try_find_reference(&update.name)?
+                    .and_then(|ref_| ref_.target());
+
+                if current != expected {
+                    return Err(git2::Error::new(
+                        git2::ErrorCode::Modified,
+                        git2::ErrorClass::Reference,
+                        format!("Reference {} was modified", update.name),
+                    ));
+                }
+            }
+
+            let message = update.log_message.as_deref().unwrap_or("Unstacked");
+            transaction.set_target(&update.name, update.target, None, message)?;
+
+            if let Some(message) = update.log_message.as_deref() {
+                let mut reflog = self.0.reflog(&update.name)?;
+                reflog.append(update.target, &self.reflog_signature()?, Some(message))?;
+                transaction.set_reflog(&update.name, reflog)?;
+            }
+        }
+
+        transaction.commit()
+    }
+
+    /// Create a worktree at `path` with a new branch, named after the last path component, that
+    /// points to the commit.
+    pub fn create_worktree(&self, path: &Path, commit: &Commit) -> Result<(), git2::Error> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| git2::Error::from_str("Worktree path needs a valid final component"))?;
+
+        let branch = self.0.branch(name, commit, false)?;
+
+        let mut options = git2::WorktreeAddOptions::new();
+        options.reference(Some(branch.get()));
+
+        self.0.worktree(name, path, Some(&options))?;
+
+        Ok(())
+    }
+
     pub fn push(&self, remote: impl AsRef<str>, refspecs: &[&str]) -> Result<(), git2::Error> {
         let mut remote = self.0.find_remote(remote.as_ref())?;
 
@@ -169,6 +443,40 @@ impl Repo {
         Ok(Commit(commit))
     }
 
+    pub fn tags_for_commit(&self, oid: Oid) -> Result<Vec<String>, git2::Error> {
+        let mut tags = Vec::new();
+
+        for ref_ in self.0.references_glob("refs/tags/*")? {
+            let ref_ = ref_?;
+
+            // Tags may point to objects other than commits, those are never a match.
+            let points_to_oid = ref_
+                .peel_to_commit()
+                .map(|commit| commit.id() == oid)
+                .unwrap_or(false);
+
+            if points_to_oid {
+                if let Some(name) = ref_.shorthand() {
+                    tags.push(name.to_string());
+                }
+            }
+        }
+
+        Ok(tags)
+    }
+
+    /// Diff the trees of two commits. Without `from`, the diff is against the empty tree.
+    pub fn diff_commits<'a>(
+        &'a self,
+        from: Option<&Commit<'a>>,
+        to: &Commit<'a>,
+        opts: Option<&mut git2::DiffOptions>,
+    ) -> Result<Diff<'a>, git2::Error> {
+        let from_tree = from.map(|from| from.tree()).transpose()?;
+        self.0
+            .diff_tree_to_tree(from_tree.as_ref(), Some(&to.tree()?), opts)
+    }
+
     pub fn staged_changes(&self) -> Result<Diff, git2::Error> {
         self.0.diff_tree_to_index(
             Some(&self.head_commit()?.tree()?),
@@ -181,6 +489,64 @@ impl Repo {
         self.0.diff_index_to_workdir(None, None)
     }
 
+    pub fn uncommitted_changes(&self) -> Result<(usize, usize), git2::Error> {
+        let staged = self.staged_changes()?.deltas().len();
+        let unstaged = self.unstaged_changes()?.deltas().len();
+        Ok((staged, unstaged))
+    }
+
+    pub fn is_rebase_in_progress(&self) -> bool {
+        matches!(
+            self.0.state(),
+            git2::RepositoryState::Rebase
+                | git2::RepositoryState::RebaseInteractive
+                | git2::RepositoryState::RebaseMerge
+                | git2::RepositoryState::ApplyMailboxOrRebase
+        )
+    }
+
+    pub fn is_cherry_pick_in_progress(&self) -> bool {
+        matches!(
+            self.0.state(),
+            git2::RepositoryState::CherryPick | git2::RepositoryState::CherryPickSequence
+        )
+    }
+
+    pub fn is_pushed(&self, oid: Oid) -> Result<bool, git2::Error> {
+        for ref_ in self.0.references_glob("refs/remotes/*")? {
+            let tip = match ref_?.peel_to_commit() {
+                Ok(tip) => tip.id(),
+                Err(_) => continue,
+            };
+
+            if tip == oid || self.0.graph_descendant_of(tip, oid)? {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    pub fn ensure_not_pushed(&self, oid: Oid) -> Result<(), Error> {
+        if self.is_pushed(oid)? {
+            return Err(Error::AlreadyPushed { commit: oid });
+        }
+
+        Ok(())
+    }
+
+    pub fn ensure_no_operation_in_progress(&self) -> Result<(), Error> {
+        if self.is_rebase_in_progress() {
+            return Err(Error::RebaseInProgress);
+        }
+
+        if self.is_cherry_pick_in_progress() {
+            return Err(Error::CherryPickInProgress);
+        }
+
+        Ok(())
+    }
+
     pub fn index_is_clean(&self) -> bool {
         self.staged_changes()
             .map(|changes| changes.deltas().len() == 0)
@@ -198,14 +564,47 @@ impl Repo {
     }
 
     pub fn unstaged_tree(&self, index_tree: &git2::Tree) -> Result<git2::Tree, Error> {
-        let unstaged_changes = self.0.diff_tree_to_workdir(Some(index_tree), None)?;
-        let mut workdir = self.apply_to_tree(index_tree, &unstaged_changes, None)?;
+        let workdir = self.0.workdir().ok_or(Error::NoWorkingDir)?;
+
+        // Submodules must be compared too so that their pointers can be updated below
+        let mut diff_opts = git2::DiffOptions::new();
+        diff_opts.ignore_submodules(false);
+        let unstaged_changes = self
+            .0
+            .diff_tree_to_workdir(Some(index_tree), Some(&mut diff_opts))?;
+
+        // Blobs are created from the files on disk rather than by applying the diff so that they
+        // pass through the clean filters (e.g. `core.autocrlf` or `text` attributes), just like
+        // they would when staged with `git add`.
+        let mut builder = git2::build::TreeUpdateBuilder::new();
+        for delta in unstaged_changes.deltas() {
+            match delta.status() {
+                git2::Delta::Deleted => {
+                    if let Some(path) = delta.old_file().path() {
+                        builder.remove(path);
+                    }
+                }
+
+                _ => {
+                    let new_file = delta.new_file();
+                    let Some(path) = new_file.path() else {
+                        continue;
+                    };
 
-        if workdir.has_conflicts() {
-            Err(Error::WorkingDirConflicts)?
+                    let blob = match new_file.mode() {
+                        // Submodules are recorded as gitlinks pointing at the checked out commit.
+                        // Uninitialised submodules have no such commit, so we keep the recorded
+                        // pointer.
+                        git2::FileMode::Commit if new_file.id().is_zero() => continue,
+                        git2::FileMode::Commit => new_file.id(),
+                        _ => self.0.blob_path(&workdir.join(path))?,
+                    };
+                    builder.upsert(path, blob, new_file.mode());
+                }
+            }
         }
 
-        let wt_tree_id = workdir.write_tree_to(&self.0)?;
+        let wt_tree_id = builder.create_updated(&self.0, index_tree)?;
         let wt_tree = self.0.find_tree(wt_tree_id)?;
 
         Ok(wt_tree)
@@ -227,7 +626,96 @@ impl Repo {
         Ok(tree)
     }
 
-    pub fn goto(&self, commit: &Commit) -> Result<(), Error> {
+    /// Move HEAD to the commit, keeping the index and working directory as they are.
+    pub fn reset_soft_to(&self, commit: &git2::Commit) -> Result<(), git2::Error> {
+        self.0.reset(commit.as_object(), ResetType::Soft, None)
+    }
+
+    /// Move HEAD to the commit and reset the index to it, keeping the working directory as is.
+    pub fn reset_index_to(&self, commit: &git2::Commit) -> Result<(), git2::Error> {
+        self.0.reset(commit.as_object(), ResetType::Mixed, None)
+    }
+
+    /// Move HEAD to the commit and reset both index and working directory to it.
+    pub fn reset_hard_to(&self, commit: &git2::Commit) -> Result<(), git2::Error> {
+        self.0.reset(commit.as_object(), ResetType::Hard, None)
+    }
+
+    /// Replace the entries in `base` that match `pathspec` with those from `source`.
+    fn overlay_paths(
+        &self,
+        base: &git2::Tree,
+        source: &git2::Tree,
+        pathspec: &git2::Pathspec,
+    ) -> Result<git2::Tree<'_>, Error> {
+        let mut index = git2::Index::new()?;
+        index.read_tree(base)?;
+
+        let mut source_index = git2::Index::new()?;
+        source_index.read_tree(source)?;
+
+        for tree in [base, source] {
+            let matches = pathspec.match_tree(tree, git2::PathspecFlags::DEFAULT)?;
+
+            for path in matches.entries() {
+                let path = Path::new(std::str::from_utf8(path)?);
+
+                match source_index.get_path(path, 0) {
+                    Some(entry) => index.add(&entry)?,
+                    None => index.remove_path(path)?,
+                }
+            }
+        }
+
+        let tree = self.0.find_tree(index.write_tree_to(&self.0)?)?;
+        Ok(tree)
+    }
+
+    /// Apply only the hunks of `diff` whose position is marked in `selected` to `tree`.
+    pub fn apply_hunks(
+        &self,
+        tree: &git2::Tree,
+        diff: &Diff,
+        selected: &[bool],
+    ) -> Result<git2::Tree<'_>, Error> {
+        // Selections are indexed like diffs::hunk_summaries: one entry per hunk, or per delta if
+        // it has no hunks at all.
+        let hunks = diffs::hunk_summaries(diff)?.len();
+        if selected.len() != hunks {
+            return Err(Error::HunkSelectionMismatch {
+                selected: selected.len(),
+                hunks,
+            });
+        }
+
+        let hunkless = diffs::hunkless_deltas(diff)?;
+        let delta_index = Cell::new(0);
+        let item_index = Cell::new(0);
+        let take_item = || {
+            let keep = selected[item_index.get()];
+            item_index.set(item_index.get() + 1);
+            keep
+        };
+
+        let mut options = git2::ApplyOptions::new();
+        options.delta_callback(|_delta| {
+            let index = delta_index.get();
+            delta_index.set(index + 1);
+
+            match hunkless.get(index) {
+                Some(true) => take_item(),
+                _ => true,
+            }
+        });
+        options.hunk_callback(|_hunk| take_item());
+
+        let mut index = self.0.apply_to_tree(tree, diff, Some(&mut options))?;
+        let tree = self.0.find_tree(index.write_tree_to(&self.0)?)?;
+
+        Ok(tree)
+    }
+
+    pub fn goto(&self, commit: &Commit, preserve: &[String]) -> Result<(), Error> {
         // Index
         let mut index = self.0.index()?;
         index.read(false)?;
@@ -249,19 +737,43 @@ impl Repo {
         // Obtain working directory changes relative to the new index tree
         let workdir_tree = self.unstaged_tree(&new_index_tree)?;
 
+        // Preserved paths are left out of the rebase below and restored from the working directory
+        // afterwards.
+        let pathspec = git2::Pathspec::new(preserve)?;
+        let rebased_workdir_tree = if preserve.is_empty() {
+            workdir_tree.clone()
+        } else {
+            self.overlay_paths(&workdir_tree, &head_tree, &pathspec)?
+        };
+
         // Rebase the working directory changes on top of the destination tree
         let new_workdir_tree = self
-            .reapply_tree_changes(&head_tree, &workdir_tree, &target_tree)
+            .reapply_tree_changes(&head_tree, &rebased_workdir_tree, &target_tree)
             .map_err(|err| match err {
                 Error::Conflicts => Error::WorkingDirConflicts,
                 other => other,
             })?;
 
-        // Move HEAD
-        self.reset(commit.as_object(), ResetType::Hard, None)?;
+        let new_workdir_tree = if preserve.is_empty() {
+            new_workdir_tree
+        } else {
+            self.overlay_paths(&new_workdir_tree, &workdir_tree, &pathspec)?
+        };
+
+        // Navigation lands on synthetic commits which are usually not a branch tip, so we never
+        // want to drag the current branch along.
+        self.set_head_detached(commit.id())?;
 
-        // Ensure working tree has the right changes.
-        self.checkout_tree(new_workdir_tree.as_object(), None)?;
+        if new_workdir_tree.id() == workdir_tree.id() {
+            // The working directory already has the right contents, so we only move HEAD.
+            self.reset_soft_to(commit)?;
+        } else {
+            // Move HEAD
+            self.reset_hard_to(commit)?;
+
+            // Ensure working tree has the right changes.
+            self.checkout_tree(new_workdir_tree.as_object(), None)?;
+        }
 
         // [checkout_tree] above also updates the index, so we need to reset that one.
         index.read_tree(&new_index_tree)?;
@@ -270,3 +782,523 @@ impl Repo {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::two_hunks;
+
+    #[test]
+    fn ref_exists_once_created() {
+        let (repo, _dir) = Repo::temporary();
+        let commit = repo.commit_files(None, &[("file", "a\n")]);
+
+        assert!(!repo.ref_exists("refs/unstacked/state").unwrap());
+        assert!(repo
+            .try_find_reference("refs/unstacked/state")
+            .unwrap()
+            .is_none());
+
+        repo.update_reference("refs/unstacked/state", commit.id())
+            .unwrap();
+
+        assert!(repo.ref_exists("refs/unstacked/state").unwrap());
+        assert!(repo
+            .try_find_reference("refs/unstacked/state")
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn ref_exists_reports_invalid_names() {
+        let (repo, _dir) = Repo::temporary();
+        assert!(repo.ref_exists("refs/heads/..").is_err());
+    }
+
+    #[test]
+    fn unstaged_tree_applies_clean_filters() {
+        let (repo, dir) = Repo::temporary();
+        repo.config()
+            .unwrap()
+            .set_bool("core.autocrlf", true)
+            .unwrap();
+        let commit = repo.commit_files(None, &[("file", "a\n"), ("gone", "b\n")]);
+
+        std::fs::write(dir.path().join("file"), "a\r\nb\r\n").unwrap();
+        let tree = repo.unstaged_tree(&commit.tree().unwrap()).unwrap();
+
+        let entry = tree.get_name("file").unwrap();
+        let blob = repo.0.find_blob(entry.id()).unwrap();
+        assert_eq!(blob.content(), b"a\nb\n");
+
+        // Files that are not on disk are removed from the tree
+        assert!(tree.get_name("gone").is_none());
+    }
+
+    #[test]
+    fn diff_commits_between_commits() {
+        let (repo, _dir) = Repo::temporary();
+        let root = repo.commit_files(None, &[("a", "a\n"), ("b", "b\n")]);
+        let child = repo.commit_files(Some(&root), &[("b", "changed\n")]);
+
+        let diff = repo.diff_commits(Some(&root), &child, None).unwrap();
+        let paths = diff
+            .deltas()
+            .map(|delta| delta.new_file().path().unwrap().to_path_buf())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, [Path::new("b")]);
+    }
+
+    #[test]
+    fn diff_commits_from_the_empty_tree() {
+        let (repo, _dir) = Repo::temporary();
+        let root = repo.commit_files(None, &[("a", "a\n"), ("b", "b\n")]);
+
+        let diff = repo.diff_commits(None, &root, None).unwrap();
+        assert_eq!(diff.deltas().len(), 2);
+        assert!(diff
+            .deltas()
+            .all(|delta| delta.status() == git2::Delta::Added));
+    }
+
+    #[test]
+    fn uncommitted_changes_counts_staged_and_unstaged() {
+        let (repo, dir) = Repo::temporary();
+        let commit = repo.commit_files(None, &[("a", "a\n"), ("b", "b\n"), ("c", "c\n")]);
+        repo.reset(commit.as_object(), ResetType::Hard, None)
+            .unwrap();
+        assert_eq!(repo.uncommitted_changes().unwrap(), (0, 0));
+
+        std::fs::write(dir.path().join("a"), "staged\n").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("a")).unwrap();
+        index.write().unwrap();
+
+        std::fs::write(dir.path().join("b"), "unstaged\n").unwrap();
+        std::fs::write(dir.path().join("c"), "unstaged\n").unwrap();
+
+        assert_eq!(repo.uncommitted_changes().unwrap(), (1, 2));
+    }
+
+    #[test]
+    fn find_commit_resolves_revision_specs() {
+        let (repo, _dir) = Repo::temporary();
+        let first = repo.commit_files(None, &[("a", "a\n")]);
+        let second = repo.commit_files(Some(&first), &[("a", "b\n")]);
+        repo.reset(first.as_object(), ResetType::Soft, None)
+            .unwrap();
+        repo.reset(second.as_object(), ResetType::Soft, None)
+            .unwrap();
+
+        assert_eq!(repo.find_commit("HEAD").unwrap().id(), second.id());
+        assert_eq!(repo.find_commit("HEAD~1").unwrap().id(), first.id());
+        assert_eq!(repo.find_commit("HEAD@{1}").unwrap().id(), first.id());
+
+        // Specs that do not name a commit are errors rather than panics
+        assert!(repo.find_commit("@{upstream}").is_err());
+        assert!(repo.find_commit("HEAD~5").is_err());
+    }
+
+    #[test]
+    fn update_references_batch_sets_all() {
+        let (repo, _dir) = Repo::temporary();
+        let base = repo.commit_files(None, &[("file", "a\n")]);
+        let commit = repo.commit_files(Some(&base), &[("file", "b\n")]);
+        repo.update_reference("refs/unstacked/b", base.id())
+            .unwrap();
+
+        let updates = [("a", commit.id()), ("b", commit.id()), ("c", base.id())]
+            .map(|(name, oid)| (format!("refs/unstacked/{name}"), oid));
+        repo.update_references_batch(updates.clone()).unwrap();
+
+        for (name, oid) in updates {
+            assert_eq!(repo.find_reference(&name).unwrap().target(), Some(oid));
+        }
+    }
+
+    #[test]
+    fn update_references_batch_checks_expected_targets() {
+        let (repo, _dir) = Repo::temporary();
+        let base = repo.commit_files(None, &[("file", "a\n")]);
+        let commit = repo.commit_files(Some(&base), &[("file", "b\n")]);
+        repo.update_reference("refs/unstacked/a", base.id())
+            .unwrap();
+
+        let result = repo.update_references_batch([
+            RefUpdate::set("refs/unstacked/b", commit.id()),
+            RefUpdate::set("refs/unstacked/a", commit.id()).expecting(Some(commit.id())),
+        ]);
+        assert_eq!(result.unwrap_err().code(), git2::ErrorCode::Modified);
+        assert!(!repo.ref_exists("refs/unstacked/b").unwrap());
+
+        repo.update_references_batch([
+            RefUpdate::set("refs/unstacked/a", commit.id()).expecting(Some(base.id())),
+            RefUpdate::set("refs/unstacked/b", commit.id()).expecting(None),
+        ])
+        .unwrap();
+        assert!(repo.ref_exists("refs/unstacked/b").unwrap());
+    }
+
+    #[test]
+    fn update_references_batch_keeps_reflogs() {
+        let (repo, _dir) = Repo::temporary();
+        let base = repo.commit_files(None, &[("file", "a\n")]);
+        let commit = repo.commit_files(Some(&base), &[("file", "b\n")]);
+
+        for (oid, message) in [(base.id(), "first"), (commit.id(), "second")] {
+            repo.update_references_batch([RefUpdate::set("refs/unstacked/a", oid).logged(message)])
+                .unwrap();
+        }
+
+        let reflog = repo.reflog("refs/unstacked/a").unwrap();
+        let entries = reflog
+            .iter()
+            .map(|entry| (entry.id_new(), entry.message().unwrap().to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            [
+                (commit.id(), "second".to_string()),
+                (base.id(), "first".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn operations_in_progress() {
+        let (repo, _dir) = Repo::temporary();
+        let commit = repo.commit_files(None, &[("a", "a\n")]);
+        repo.reset(commit.as_object(), ResetType::Hard, None)
+            .unwrap();
+        assert!(repo.ensure_no_operation_in_progress().is_ok());
+
+        let cherry_pick_head = repo.path().join("CHERRY_PICK_HEAD");
+        std::fs::write(&cherry_pick_head, format!("{}\n", commit.id())).unwrap();
+        assert!(repo.is_cherry_pick_in_progress());
+        assert!(!repo.is_rebase_in_progress());
+        assert!(matches!(
+            repo.ensure_no_operation_in_progress(),
+            Err(Error::CherryPickInProgress)
+        ));
+        std::fs::remove_file(cherry_pick_head).unwrap();
+
+        std::fs::create_dir(repo.path().join("rebase-merge")).unwrap();
+        assert!(repo.is_rebase_in_progress());
+        assert!(!repo.is_cherry_pick_in_progress());
+        assert!(matches!(
+            repo.ensure_no_operation_in_progress(),
+            Err(Error::RebaseInProgress)
+        ));
+    }
+
+    #[test]
+    fn goto_leaves_an_up_to_date_working_directory_alone() {
+        let (repo, dir) = Repo::temporary();
+        let first = repo.commit_files(None, &[("a", "a\n")]);
+        let second = repo.commit_files(Some(&first), &[]);
+        repo.reset(first.as_object(), ResetType::Hard, None)
+            .unwrap();
+
+        // An unstaged change, dated in the past to tell whether the file is written again
+        let file = dir.path().join("a");
+        std::fs::write(&file, "changed\n").unwrap();
+        let past = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1 << 30);
+        std::fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(past)
+            .unwrap();
+
+        repo.goto(&second, &[]).unwrap();
+
+        assert_eq!(repo.head_commit().unwrap().id(), second.id());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "changed\n");
+        assert_eq!(std::fs::metadata(&file).unwrap().modified().unwrap(), past);
+    }
+
+    #[test]
+    fn goto_detaches_head() {
+        let (repo, _dir) = Repo::temporary();
+        let first = repo.commit_files(None, &[("a", "a\n")]);
+        let second = repo.commit_files(Some(&first), &[("a", "b\n")]);
+        repo.reset(first.as_object(), ResetType::Hard, None)
+            .unwrap();
+
+        let branch = repo.head().unwrap().shorthand().unwrap().to_string();
+        assert_eq!(
+            repo.head_state().unwrap(),
+            HeadState::Branch(branch.clone())
+        );
+
+        repo.goto(&second, &[]).unwrap();
+        assert_eq!(repo.head_state().unwrap(), HeadState::Detached(second.id()));
+        assert_eq!(
+            repo.head_state().unwrap().to_string(),
+            format!("detached at {}", second.id())
+        );
+
+        // The branch stays where it was
+        let branch = repo.find_branch(&branch, git2::BranchType::Local).unwrap();
+        assert_eq!(branch.get().target(), Some(first.id()));
+    }
+
+    #[test]
+    fn tags_for_commit_finds_lightweight_and_annotated_tags() {
+        let (repo, _dir) = Repo::temporary();
+        let first = repo.commit_files(None, &[("a", "a\n")]);
+        let second = repo.commit_files(Some(&first), &[("a", "b\n")]);
+        assert!(repo.tags_for_commit(first.id()).unwrap().is_empty());
+
+        let sig = repo.signature().unwrap();
+        repo.tag_lightweight("light", first.as_object(), false)
+            .unwrap();
+        repo.tag("annotated", first.as_object(), &sig, "Annotated", false)
+            .unwrap();
+        repo.tag_lightweight("other", second.as_object(), false)
+            .unwrap();
+
+        // A tag of a blob is never a match
+        let blob = repo.find_object(repo.blob(b"blob").unwrap(), None).unwrap();
+        repo.tag_lightweight("blob", &blob, false).unwrap();
+
+        let mut tags = repo.tags_for_commit(first.id()).unwrap();
+        tags.sort();
+        assert_eq!(tags, ["annotated", "light"]);
+    }
+
+    /// Commit on top of `parent` that records the submodule `sub` at `pointer`
+    fn commit_gitlink<'a>(repo: &'a Repo, parent: &Commit<'a>, pointer: Oid) -> Commit<'a> {
+        let mut builder = git2::build::TreeUpdateBuilder::new();
+        builder.upsert("sub", pointer, git2::FileMode::Commit);
+        let tree = builder
+            .create_updated(repo, &parent.tree().unwrap())
+            .unwrap();
+        let tree = repo.find_tree(tree).unwrap();
+
+        let sig = repo.signature().unwrap();
+        repo.commit(&sig, &sig, "Submodule", &tree, [parent])
+            .unwrap()
+    }
+
+    #[test]
+    fn unstaged_tree_records_submodule_pointers() {
+        let (repo, dir) = Repo::temporary();
+        let base = repo.commit_files(None, &[("a", "a\n")]);
+
+        let recorded = repo.commit_files(None, &[("s", "1\n")]);
+        let commit = commit_gitlink(&repo, &base, recorded.id());
+        repo.reset(commit.as_object(), ResetType::Hard, None)
+            .unwrap();
+
+        // The submodule is checked out at a different commit than the one recorded
+        let sub = Repo(git2::Repository::init(dir.path().join("sub")).unwrap());
+        let mut config = sub.config().unwrap();
+        config.set_str("user.name", "Test").unwrap();
+        config.set_str("user.email", "test@example.com").unwrap();
+        let checked_out = sub.commit_files(None, &[("s", "2\n")]);
+        sub.set_head_detached(checked_out.id()).unwrap();
+
+        let tree = repo.unstaged_tree(&commit.tree().unwrap()).unwrap();
+        let entry = tree.get_name("sub").unwrap();
+        assert_eq!(entry.filemode(), i32::from(git2::FileMode::Commit));
+        assert_eq!(entry.id(), checked_out.id());
+    }
+
+    #[test]
+    fn unstaged_tree_keeps_uninitialised_submodules() {
+        let (repo, dir) = Repo::temporary();
+        let base = repo.commit_files(None, &[("a", "a\n")]);
+        let pointer = repo.blob(b"not a commit").unwrap();
+        let commit = commit_gitlink(&repo, &base, pointer);
+        repo.reset(commit.as_object(), ResetType::Hard, None)
+            .unwrap();
+        assert!(dir.path().join("sub").is_dir());
+
+        let tree = repo.unstaged_tree(&commit.tree().unwrap()).unwrap();
+        assert_eq!(tree.id(), commit.tree_id());
+    }
+
+    #[test]
+    fn goto_leaves_preserved_paths_untouched() {
+        let (repo, dir) = Repo::temporary();
+        let first = repo.commit_files(None, &[("a", "1\n"), ("local.cfg", "first\n")]);
+        let second = repo.commit_files(Some(&first), &[("a", "2\n"), ("local.cfg", "second\n")]);
+        repo.reset(second.as_object(), ResetType::Hard, None)
+            .unwrap();
+
+        let local = dir.path().join("local.cfg");
+        std::fs::write(&local, "mine\n").unwrap();
+
+        // The local change conflicts with the target unless it is preserved
+        assert!(matches!(
+            repo.goto(&first, &[]),
+            Err(Error::WorkingDirConflicts)
+        ));
+
+        repo.goto(&first, &["local.cfg".to_string()]).unwrap();
+        assert_eq!(repo.head_commit().unwrap().id(), first.id());
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("a")).unwrap(),
+            "1\n"
+        );
+        assert_eq!(std::fs::read_to_string(&local).unwrap(), "mine\n");
+    }
+
+    #[test]
+    fn apply_hunks_takes_only_selected_changes() {
+        let (repo, _dir) = Repo::temporary();
+        let (old, new) = two_hunks();
+        let base = repo.commit_files(None, &[("a", old.as_str())]);
+        let changed = repo.commit_files(Some(&base), &[("a", new.as_str()), ("empty", "")]);
+        let diff = repo.diff_commits(Some(&base), &changed, None).unwrap();
+
+        // Hunks of "a", then the hunkless new file
+        let tree = repo
+            .apply_hunks(&base.tree().unwrap(), &diff, &[false, true, true])
+            .unwrap();
+        let blob = repo.find_blob(tree.get_name("a").unwrap().id()).unwrap();
+        let expected = old.replace("20\n", "last\n");
+        assert_eq!(blob.content(), expected.as_bytes());
+        assert!(tree.get_name("empty").is_some());
+
+        let tree = repo
+            .apply_hunks(&base.tree().unwrap(), &diff, &[true, false, false])
+            .unwrap();
+        assert!(tree.get_name("empty").is_none());
+        assert!(matches!(
+            repo.apply_hunks(&base.tree().unwrap(), &diff, &[true, false]),
+            Err(Error::HunkSelectionMismatch {
+                selected: 2,
+                hunks: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn commits_reachable_from_remotes_are_pushed() {
+        let (repo, _dir) = Repo::temporary();
+        let first = repo.commit_files(None, &[("a", "1\n")]);
+        let second = repo.commit_files(Some(&first), &[("a", "2\n")]);
+        let third = repo.commit_files(Some(&second), &[("a", "3\n")]);
+        assert!(!repo.is_pushed(first.id()).unwrap());
+
+        repo.reference("refs/remotes/origin/main", second.id(), false, "test")
+            .unwrap();
+
+        assert!(repo.is_pushed(first.id()).unwrap());
+        assert!(repo.is_pushed(second.id()).unwrap());
+        assert!(!repo.is_pushed(third.id()).unwrap());
+        assert!(repo.ensure_not_pushed(third.id()).is_ok());
+        assert!(matches!(
+            repo.ensure_not_pushed(second.id()),
+            Err(Error::AlreadyPushed { commit }) if commit == second.id()
+        ));
+    }
+
+    #[test]
+    fn commit_with_trailers_appends_them() {
+        let (repo, _dir) = Repo::temporary();
+        let base = repo.commit_files(None, &[("a", "1\n")]);
+        let sig = repo.signature().unwrap();
+        let trailers = ["Fixes: #12".parse().unwrap()];
+
+        let commit = repo
+            .commit_with_trailers(
+                &sig,
+                &sig,
+                "Subject",
+                &trailers,
+                &base.tree().unwrap(),
+                [&base],
+            )
+            .unwrap();
+        assert_eq!(commit.message(), Some("Subject\n\nFixes: #12\n"));
+        assert_eq!(commit.parent_id(0).unwrap(), base.id());
+    }
+
+    #[test]
+    fn verify_unsigned_commits() {
+        let (repo, _dir) = Repo::temporary();
+        let commit = repo.commit_files(None, &[("a", "1\n")]);
+        assert_eq!(
+            repo.verify_commit(commit.id()).unwrap(),
+            SignatureStatus::Unsigned
+        );
+
+        repo.config().unwrap().set_str("gpg.format", "ssh").unwrap();
+        assert!(matches!(
+            repo.verify_commit(commit.id()),
+            Err(Error::UnsupportedSignatureFormat { format }) if format == "ssh"
+        ));
+    }
+
+    #[test]
+    fn commits_in_range_of_both_kinds() {
+        let (repo, _dir) = Repo::temporary();
+        let base = repo.commit_files(None, &[("a", "1\n")]);
+        let left = repo.commit_files(Some(&base), &[("b", "1\n")]);
+        let right = repo.commit_files(Some(&base), &[("c", "1\n")]);
+        let right_tip = repo.commit_files(Some(&right), &[("c", "2\n")]);
+
+        let two_dot = repo
+            .commits_in_range(&format!("{}..{}", left.id(), right_tip.id()))
+            .unwrap();
+        assert_eq!(two_dot, [right.id(), right_tip.id()]);
+
+        let mut three_dot = repo
+            .commits_in_range(&format!("{}...{}", left.id(), right_tip.id()))
+            .unwrap();
+        three_dot.sort();
+        let mut expected = vec![left.id(), right.id(), right_tip.id()];
+        expected.sort();
+        assert_eq!(three_dot, expected);
+
+        assert!(repo.commits_in_range(&left.id().to_string()).is_err());
+    }
+
+    #[test]
+    fn reset_helpers() {
+        let (repo, dir) = Repo::temporary();
+        let first = repo.commit_files(None, &[("a", "1\n")]);
+        let second = repo.commit_files(Some(&first), &[("a", "2\n")]);
+        repo.reset_hard_to(&first).unwrap();
+
+        repo.reset_soft_to(&second).unwrap();
+        assert_eq!(repo.head_commit().unwrap().id(), second.id());
+        assert_eq!(repo.uncommitted_changes().unwrap(), (1, 0));
+
+        repo.reset_index_to(&second).unwrap();
+        assert_eq!(repo.uncommitted_changes().unwrap(), (0, 1));
+
+        repo.reset_hard_to(&second).unwrap();
+        assert_eq!(repo.uncommitted_changes().unwrap(), (0, 0));
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("a")).unwrap(),
+            "2\n"
+        );
+    }
+
+    #[test]
+    fn create_worktree_at_commit() {
+        let (repo, _dir) = Repo::temporary();
+        let first = repo.commit_files(None, &[("a", "1\n")]);
+        let second = repo.commit_files(Some(&first), &[("a", "2\n")]);
+        repo.reset_hard_to(&second).unwrap();
+
+        let worktree_dir = temp_dir::TempDir::new().unwrap();
+        let path = worktree_dir.path().join("feature");
+        repo.create_worktree(&path, &first).unwrap();
+
+        let worktree = Repo::discover(&path).unwrap();
+        assert!(worktree.is_worktree());
+        assert_eq!(worktree.head_commit().unwrap().id(), first.id());
+        assert_eq!(std::fs::read_to_string(path.join("a")).unwrap(), "1\n");
+
+        // The main working directory is left alone
+        assert_eq!(repo.head_commit().unwrap().id(), second.id());
+
+        // Names are taken from the path and must not clash
+        assert!(repo.create_worktree(&path, &first).is_err());
+    }
+}
@@ -1,20 +1,32 @@
 use crate::{
-    commit::{self, Commit},
+    commit::{self, Commit, MergeTool, Trailer},
     diffs,
-    repo::{self, Repo},
+    repo::{self, RefUpdate, Repo},
 };
-use git2::{Oid, ResetType, Signature};
+use git2::{Oid, Signature};
 use serde::{Deserialize, Serialize};
-use std::{env, fmt, fs, io, path, process, string::FromUtf8Error};
+use std::{
+    collections::HashMap, env, fmt, fs, io, mem, path, process, string::FromUtf8Error, time,
+};
 use termion::color::*;
 
 pub struct Manager {
     repo: Repo,
+
+    /// Overwrite the state even if it was changed since it was read
+    force_state: bool,
 }
 
 impl Manager {
     pub fn new(repo: Repo) -> Self {
-        Self { repo }
+        Self {
+            repo,
+            force_state: false,
+        }
+    }
+
+    pub fn set_force_state(&mut self, force_state: bool) {
+        self.force_state = force_state;
     }
 
     pub fn repo(&self) -> &Repo {
@@ -39,21 +51,76 @@ impl Manager {
         self.dot_git_child("COMMITINFO_EDIT")
     }
 
+    fn hunk_selection_file(&self) -> path::PathBuf {
+        self.dot_git_child("HUNKS_EDIT")
+    }
+
+    pub fn select_hunks(&self, diff: &git2::Diff) -> Result<Vec<bool>, Error> {
+        let summaries = diffs::hunk_summaries(diff)?;
+
+        let mut body = summaries
+            .iter()
+            .enumerate()
+            .map(|(index, summary)| format!("{index} {summary}\n"))
+            .collect::<String>();
+        body.push_str("\n# Remove the lines of the hunks that should be left out.\n");
+
+        let edited = self.compose_message_plain(&self.hunk_selection_file(), body)?;
+
+        let mut selected = vec![false; summaries.len()];
+        for line in edited.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let index = line
+                .split_whitespace()
+                .next()
+                .and_then(|index| index.parse::<usize>().ok())
+                .filter(|index| *index < selected.len())
+                .ok_or_else(|| Error::InvalidHunkSelection {
+                    line: line.to_string(),
+                })?;
+
+            selected[index] = true;
+        }
+
+        Ok(selected)
+    }
+
+    /// Editor to use: `core.editor`, or else `$EDITOR`
+    fn editor(&self) -> Result<String, Error> {
+        match self.repo.config()?.get_string("core.editor") {
+            Ok(editor) => Ok(editor),
+            Err(err) if err.code() == git2::ErrorCode::NotFound => {
+                Ok(env::var("EDITOR").unwrap_or_else(|_| "vi".to_string()))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
     pub fn compose_message_plain(
         &self,
         msg_file: &path::PathBuf,
         body: String,
     ) -> Result<String, Error> {
-        let editor = env::var("EDITOR").expect("Need $EDITOR set when omitting commit message");
+        let editor = self.editor()?;
 
         fs::write(msg_file, body)?;
 
-        let exit = process::Command::new(editor)
+        // Like git, run the editor through the shell so that it may come with arguments
+        let exit = process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("{editor} \"$@\""))
+            .arg(&editor)
             .arg(msg_file)
             .spawn()?
             .wait()?;
 
-        assert!(exit.success());
+        if !exit.success() {
+            fs::remove_file(msg_file)?;
+            return Err(Error::EditorAborted);
+        }
 
         let msg = fs::read(msg_file)?;
         let msg = String::from_utf8(msg)?;
@@ -98,6 +165,25 @@ impl Manager {
         Ok(msg)
     }
 
+    pub fn commit_template(
+        &self,
+        template_file: Option<&path::Path>,
+    ) -> Result<Option<String>, Error> {
+        let template_file = match template_file {
+            Some(template_file) => template_file.to_path_buf(),
+            None => match self.repo.config()?.get_path("commit.template") {
+                Ok(template_file) => template_file,
+                Err(err) if err.code() == git2::ErrorCode::NotFound => return Ok(None),
+                Err(err) => return Err(err.into()),
+            },
+        };
+
+        let template = fs::read(template_file)?;
+        let template = String::from_utf8(template)?;
+
+        Ok(Some(template))
+    }
+
     pub fn compose_commit_message(
         &self,
         headline: Option<String>,
@@ -153,13 +239,184 @@ impl Manager {
         )?;
         let new_head = self.repo.0.find_commit(new_head)?;
 
-        self.repo
-            .0
-            .reset(new_head.as_object(), ResetType::Soft, None)?;
+        self.repo.reset_soft_to(&new_head)?;
+
+        Ok(MoveResult::moved(&head, &new_head))
+    }
+
+    /// Check that `target` can be reworded: it must be HEAD or one of its ancestors, and unless
+    /// `force` is given, neither pushed nor followed by signed commits, whose signatures would be
+    /// lost when recreating them.
+    pub fn check_reword(&self, target: &Commit, force: bool) -> Result<(), Error> {
+        let head = self.repo.head_commit()?;
+
+        if head.id() != target.id() && !self.repo.graph_descendant_of(head.id(), target.id())? {
+            return Err(Error::NotAncestorOfHead {
+                commit: target.id(),
+            });
+        }
+
+        if force {
+            return Ok(());
+        }
+
+        self.repo.ensure_not_pushed(target.id())?;
+
+        for oid in [target.id()].into_iter().chain(self.commits_above(target)?) {
+            if self
+                .repo
+                .0
+                .find_commit(oid)?
+                .header_field_bytes("gpgsig")
+                .is_ok()
+            {
+                return Err(Error::SignedCommit { commit: oid });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Commits between `target` and HEAD, parents first
+    fn commits_above(&self, target: &Commit) -> Result<Vec<Oid>, Error> {
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.push_head()?;
+        revwalk.hide(target.id())?;
+        revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+
+        Ok(revwalk.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// Change the message of `target` and recreate the commits between it and HEAD on top of the
+    /// result. Trees are left untouched. See [Manager::check_reword] for what `force` allows.
+    pub fn reword_commit(
+        &self,
+        target: &Commit,
+        message: &str,
+        force: bool,
+    ) -> Result<MoveResult, Error> {
+        self.check_reword(target, force)?;
+
+        let head = self.repo.head_commit()?;
+        let new_target = target.amend_message(&self.repo, message)?;
+
+        let mut rewritten = HashMap::new();
+        rewritten.insert(target.id(), new_target.id());
+
+        let mut new_head_id = new_target.id();
+        for oid in self.commits_above(target)? {
+            let commit = self.repo.0.find_commit(oid)?;
+
+            let parents = commit
+                .parent_ids()
+                .map(|parent| *rewritten.get(&parent).unwrap_or(&parent))
+                .map(|parent| self.repo.0.find_commit(parent))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            new_head_id = self.repo.0.commit(
+                None,
+                &commit.author(),
+                &commit.committer(),
+                commit.message().unwrap_or(""),
+                &commit.tree()?,
+                parents.iter().collect::<Vec<_>>().as_slice(),
+            )?;
+            rewritten.insert(commit.id(), new_head_id);
+        }
+
+        let new_head = self.repo.0.find_commit(new_head_id)?;
+
+        let wip = self.protect(new_head.id())?;
+        self.repo.reset_soft_to(&new_head)?;
+        State::read(self)?.write(self, "reword", head.id(), new_head.id())?;
+        self.release(&wip)?;
 
         Ok(MoveResult::moved(&head, &new_head))
     }
 
+    /// Paths to leave untouched when navigating, from `unstacked.preservePaths` and `extra`
+    pub fn preserve_paths(&self, extra: Vec<String>) -> Result<Vec<String>, Error> {
+        let config = self.repo.config()?;
+        let mut entries = config.multivar("unstacked.preservePaths", None)?;
+
+        let mut paths = Vec::new();
+        while let Some(entry) = entries.next() {
+            if let Some(value) = entry?.value() {
+                paths.push(value.to_string());
+            }
+        }
+
+        paths.extend(extra);
+        Ok(paths)
+    }
+
+    /// Whether to show the diff after navigating, from `flag` or `navigation.showDiff`
+    pub fn show_diff(&self, flag: bool) -> Result<bool, Error> {
+        if flag {
+            return Ok(true);
+        }
+
+        match self.repo.config()?.get_bool("navigation.showDiff") {
+            Ok(value) => Ok(value),
+            Err(err) if err.code() == git2::ErrorCode::NotFound => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Committer to stamp on new commits instead of the default one, either given explicitly or
+    /// via `GIT_COMMITTER_NAME`/`GIT_COMMITTER_EMAIL`
+    pub fn committer_override(
+        &self,
+        committer: Option<PlainSig>,
+    ) -> Result<Option<Signature<'static>>, Error> {
+        self.signature_override(
+            committer,
+            env::var("GIT_COMMITTER_NAME").ok(),
+            env::var("GIT_COMMITTER_EMAIL").ok(),
+        )
+    }
+
+    /// Author to use for new commits instead of the default one, either given explicitly or via
+    /// `GIT_AUTHOR_NAME`/`GIT_AUTHOR_EMAIL`
+    pub fn author_override(
+        &self,
+        author: Option<PlainSig>,
+    ) -> Result<Option<Signature<'static>>, Error> {
+        self.signature_override(
+            author,
+            env::var("GIT_AUTHOR_NAME").ok(),
+            env::var("GIT_AUTHOR_EMAIL").ok(),
+        )
+    }
+
+    /// Signature from `explicit`, or else from the `name` and `email` taken from the environment,
+    /// where a missing one falls back to the configured identity
+    fn signature_override(
+        &self,
+        explicit: Option<PlainSig>,
+        name: Option<String>,
+        email: Option<String>,
+    ) -> Result<Option<Signature<'static>>, Error> {
+        let sig = match explicit {
+            Some(sig) => sig,
+
+            None => {
+                if name.is_none() && email.is_none() {
+                    return Ok(None);
+                }
+
+                let default = self.repo.signature()?;
+                PlainSig {
+                    name: name.unwrap_or_else(|| default.name().unwrap_or("").to_string()),
+                    email: email.unwrap_or_else(|| default.email().unwrap_or("").to_string()),
+                }
+            }
+        };
+
+        let sig = Signature::now(sig.name.as_str(), sig.email.as_str())?;
+        Ok(Some(sig))
+    }
+
     pub fn capture_tree(&self, use_index: bool) -> Result<git2::Tree, Error> {
         let head: Commit = self.repo.head_commit()?;
 
@@ -175,6 +432,153 @@ impl Manager {
 
         Ok(tree)
     }
+
+    /// Stack that navigation operates on, from `unstacked.activeStack`
+    pub fn active_stack(&self) -> Result<String, Error> {
+        match self.repo.config()?.get_string("unstacked.activeStack") {
+            Ok(stack) => Ok(stack),
+            Err(err) if err.code() == git2::ErrorCode::NotFound => Ok(DEFAULT_STACK.to_string()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn stack_ref(stack: &str, name: &str) -> String {
+        if stack == DEFAULT_STACK {
+            format!("refs/unstacked/{name}")
+        } else {
+            format!("refs/unstacked/stacks/{stack}/{name}")
+        }
+    }
+
+    /// Reference `name` of the active stack
+    fn active_stack_ref(&self, name: &str) -> Result<String, Error> {
+        Ok(Self::stack_ref(self.active_stack()?.as_str(), name))
+    }
+
+    pub fn stacks(&self) -> Result<Vec<String>, Error> {
+        let mut stacks = vec![DEFAULT_STACK.to_string()];
+        let glob = Self::stack_ref("*", STACK_HEAD_REF);
+
+        for ref_ in self.repo.references_glob(glob.as_str())? {
+            let ref_ = ref_?;
+            let stack = ref_
+                .name()
+                .and_then(|name| name.strip_prefix("refs/unstacked/stacks/"))
+                .and_then(|name| name.strip_suffix(format!("/{STACK_HEAD_REF}").as_str()));
+
+            if let Some(stack) = stack {
+                stacks.push(stack.to_string());
+            }
+        }
+
+        stacks.sort();
+        stacks.dedup();
+        Ok(stacks)
+    }
+
+    /// Make `stack` the active stack, remembering HEAD for the stack that was active before.
+    /// Switching to an existing stack checks out the commit that was remembered for it, while a
+    /// newly created stack starts at the current HEAD.
+    pub fn switch_stack(&self, stack: &str, create: bool) -> Result<MoveResult, Error> {
+        let head_ref = Self::stack_ref(stack, STACK_HEAD_REF);
+        if !git2::Reference::is_valid_name(head_ref.as_str()) || stack.contains('/') {
+            return Err(Error::InvalidStackName {
+                name: stack.to_string(),
+            });
+        }
+
+        let exists = stack == DEFAULT_STACK || self.repo.ref_exists(head_ref.as_str())?;
+        if create && exists {
+            return Err(Error::StackExists {
+                name: stack.to_string(),
+            });
+        } else if !create && !exists {
+            return Err(Error::UnknownStack {
+                name: stack.to_string(),
+            });
+        }
+
+        let active_head_ref = self.active_stack_ref(STACK_HEAD_REF)?;
+        let head = self.repo.head_commit()?;
+
+        // Switching to the active stack stays at HEAD
+        let new_head: Commit = match self.repo.try_find_reference(head_ref.as_str())? {
+            Some(ref_) if head_ref != active_head_ref => ref_.peel_to_commit()?.into(),
+            _ => head.clone(),
+        };
+
+        if new_head.id() != head.id() {
+            self.repo
+                .goto(&new_head, &self.preserve_paths(Vec::new())?)?;
+        }
+
+        // Remember HEAD for the stack that was active and the new stack together
+        let mut updates = vec![(active_head_ref, head.id())];
+        if head_ref != updates[0].0 {
+            updates.push((head_ref, new_head.id()));
+        }
+        self.repo.update_references_batch(updates)?;
+        self.repo
+            .config()?
+            .set_str("unstacked.activeStack", stack)?;
+
+        if new_head.id() == head.id() {
+            Ok(MoveResult::stationary(&head))
+        } else {
+            Ok(MoveResult::moved(&head, &new_head))
+        }
+    }
+
+    /// Keep a freshly created commit reachable through a `refs/unstacked/wip/` reference until the
+    /// operation that created it has recorded it elsewhere. Returns the reference name.
+    fn protect(&self, commit: Oid) -> Result<String, Error> {
+        let timestamp = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+
+        let name = format!("{WIP_REF_PREFIX}{timestamp}-{}", process::id());
+        self.repo
+            .reference(name.as_str(), commit, true, "Unstacked: wip")?;
+
+        Ok(name)
+    }
+
+    fn release(&self, name: &str) -> Result<(), Error> {
+        self.repo.find_reference(name)?.delete()?;
+        Ok(())
+    }
+
+    /// Number of commits between HEAD and the commit the stack is built on, which is the latest
+    /// commit that has already been pushed, or else the root commit.
+    pub fn stack_height(&self) -> Result<usize, Error> {
+        let mut commit = self.repo.head_commit()?;
+        let mut height = 0;
+
+        while commit.parent_count() > 0 && !self.repo.is_pushed(commit.id())? {
+            commit = self.repo.0.find_commit(commit.parent_id(0)?)?.into();
+            height += 1;
+        }
+
+        Ok(height)
+    }
+
+    /// Commits left behind by operations that were interrupted before completing
+    pub fn wip_refs(&self) -> Result<Vec<(String, Oid)>, Error> {
+        let mut refs = Vec::new();
+
+        for ref_ in self
+            .repo
+            .references_glob(format!("{WIP_REF_PREFIX}*").as_str())?
+        {
+            let ref_ = ref_?;
+            if let (Some(name), Some(target)) = (ref_.name(), ref_.target()) {
+                refs.push((name.to_string(), target));
+            }
+        }
+
+        Ok(refs)
+    }
 }
 
 #[derive(Debug, derive_more::Display, derive_more::From, derive_more::Error)]
@@ -186,9 +590,79 @@ pub enum Error {
     IO(io::Error),
     Utf8(FromUtf8Error),
     EmptyMessage,
+
+    #[display(fmt = "Nothing to commit, use --allow-empty to commit anyway")]
+    EmptyCommit,
+
+    #[display(fmt = "Commit message template was not edited")]
+    TemplateUnchanged,
+
+    #[display(fmt = "Editor exited unsuccessfully")]
+    EditorAborted,
+
+    #[display(fmt = "Patch does not apply, these hunks fail:\n{hunks}")]
+    #[from(ignore)]
+    PatchDoesNotApply {
+        hunks: String,
+    },
+
+    #[display(fmt = "Invalid hunk selection: {line}")]
+    InvalidHunkSelection {
+        line: String,
+    },
+
+    #[display(
+        fmt = "Commit {commit} is signed and its signature would be lost, use --force to continue"
+    )]
+    #[from(ignore)]
+    SignedCommit {
+        commit: Oid,
+    },
+
+    #[display(fmt = "Commit {commit} is not HEAD or one of its ancestors")]
+    #[from(ignore)]
+    NotAncestorOfHead {
+        commit: Oid,
+    },
+
+    #[display(fmt = "Invalid stack name {name:?}")]
+    #[from(ignore)]
+    InvalidStackName {
+        name: String,
+    },
+
+    #[display(fmt = "Stack {name} does not exist")]
+    #[from(ignore)]
+    UnknownStack {
+        name: String,
+    },
+
+    #[display(fmt = "Stack {name} already exists")]
+    #[from(ignore)]
+    StackExists {
+        name: String,
+    },
+
+    #[display(
+        fmt = "State was changed since it was read, re-run the command or pass --force-state to \
+               overwrite it"
+    )]
+    StateChanged,
 }
 
-const STATE_REF: &str = "refs/unstacked/state";
+/// Name of the state reference within the active stack, see [Manager::active_stack_ref]
+const STATE_REF: &str = "state";
+
+/// Commit that was checked out when switching away from a stack
+const STACK_HEAD_REF: &str = "head";
+
+/// Stack whose references live directly under `refs/unstacked/`
+const DEFAULT_STACK: &str = "default";
+
+const WIP_REF_PREFIX: &str = "refs/unstacked/wip/";
+
+/// Number of context lines that is large enough to always include the entire file
+const FULL_CONTEXT_LINES: u32 = i32::MAX as u32;
 
 #[repr(transparent)]
 #[derive(Debug, derive_more::Display, Clone, Copy)]
@@ -255,6 +729,17 @@ impl MoveResult {
         }
     }
 }
+
+/// Outcome of moving several commits at once
+#[derive(Debug)]
+pub struct Steps {
+    pub result: MoveResult,
+    pub taken: usize,
+
+    /// Error that stopped the move before all steps were taken
+    pub interrupted: Option<Error>,
+}
+
 impl fmt::Display for MoveResult {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -292,59 +777,197 @@ impl fmt::Display for MoveResult {
     }
 }
 
+/// Stable, machine-readable rendering with one tab-separated field per line
+pub trait PorcelainDisplay {
+    fn fmt_porcelain(&self, fmt: &mut fmt::Formatter) -> fmt::Result;
+}
+
+/// Adapter to render a [PorcelainDisplay] via [fmt::Display]
+pub struct Porcelain<'a, T>(pub &'a T);
+
+impl<T: PorcelainDisplay> fmt::Display for Porcelain<'_, T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt_porcelain(fmt)
+    }
+}
+
+impl PorcelainDisplay for MoveResult {
+    fn fmt_porcelain(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let (head, message, author, email) = match self {
+            MoveResult::Stationary {
+                head,
+                message,
+                author,
+                email,
+            } => (head, message, author, email),
+
+            MoveResult::Moved {
+                from,
+                to,
+                message,
+                author,
+                email,
+            } => {
+                writeln!(fmt, "previous\t{from}")?;
+                (to, message, author, email)
+            }
+        };
+
+        writeln!(fmt, "commit\t{head}")?;
+        writeln!(fmt, "author\t{author} <{email}>")?;
+        writeln!(fmt, "subject\t{}", message.lines().next().unwrap_or(""))?;
+
+        Ok(())
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct State {
     next: Box<Unrealised>,
+
+    /// Blob that this state was read from, used to detect concurrent modifications
+    #[serde(skip)]
+    loaded_from: Option<Oid>,
 }
 
 impl State {
     pub fn read(mgr: &Manager) -> Result<Self, Error> {
-        match mgr.repo.find_reference(STATE_REF) {
-            Ok(ref_) => {
-                let oid = ref_.peel_to_blob()?;
-                Ok(serde_json::de::from_slice(oid.content())?)
+        let state_ref = mgr.active_stack_ref(STATE_REF)?;
+
+        let Some(reference) = mgr.repo.try_find_reference(state_ref.as_str())? else {
+            let next = Box::new(Unrealised::Stop);
+            return Ok(State {
+                next,
+                loaded_from: None,
+            });
+        };
+
+        let blob = reference.peel_to_blob()?;
+        let mut state: State = serde_json::de::from_slice(blob.content())?;
+        state.loaded_from = Some(blob.id());
+        Ok(state)
+    }
+
+    /// Save the state and record the operation that moved HEAD from `from` to `to` in the
+    /// navigation log, which is the reflog of the state reference.
+    pub fn write(
+        &mut self,
+        mgr: &Manager,
+        operation: &str,
+        from: Oid,
+        to: Oid,
+    ) -> Result<(), Error> {
+        let contents = serde_json::ser::to_vec_pretty(self)?;
+        let oid = mgr.repo.blob(contents.as_slice())?;
+        let state_ref = mgr.active_stack_ref(STATE_REF)?;
+
+        // Only replace the state we have read, so that concurrent invocations don't clobber each
+        // other's changes, unless asked to.
+        let mut update = RefUpdate::set(state_ref, oid).logged(format!("{operation} {from} {to}"));
+        if !mgr.force_state {
+            update = update.expecting(self.loaded_from);
+        }
+
+        match mgr.repo.update_references_batch([update]) {
+            Ok(()) => {
+                self.loaded_from = Some(oid);
+                Ok(())
             }
 
-            Err(git_error) if git_error.code() == git2::ErrorCode::NotFound => {
-                let next = Box::new(Unrealised::Stop);
-                Ok(State { next })
+            Err(err)
+                if err.code() == git2::ErrorCode::Modified
+                    || err.code() == git2::ErrorCode::Locked =>
+            {
+                Err(Error::StateChanged)
             }
 
             Err(err) => Err(err.into()),
         }
     }
 
-    pub fn write(&self, mgr: &Manager) -> Result<(), Error> {
-        let contents = serde_json::ser::to_vec_pretty(self)?;
-        let oid = mgr.repo.blob(contents.as_slice())?;
-        mgr.repo.update_reference(STATE_REF, oid)?;
-        Ok(())
-    }
-
     pub fn validate(self, _mgr: &Manager) -> Result<Self, Error> {
         Ok(self)
     }
 
-    pub fn prev(&mut self, mgr: &Manager) -> Result<MoveResult, Error> {
-        let head = mgr.repo.head_commit()?;
-        let parent: Commit = mgr.repo.0.find_commit(head.parent_id(0)?)?.into();
+    pub fn read_log(mgr: &Manager) -> Result<String, Error> {
+        let reflog = mgr.repo.reflog(&mgr.active_stack_ref(STATE_REF)?)?;
+
+        // The reflog starts with the latest entry
+        let log = reflog
+            .iter()
+            .rev()
+            .filter_map(|entry| {
+                let timestamp = entry.committer().when().seconds();
+                Some(format!("{timestamp} {}\n", entry.message()?))
+            })
+            .collect();
 
-        self.next = Box::new(Unrealised::Commit {
-            next: self.next.clone(),
-            commit: PlainOid(head.id()),
-        });
+        Ok(log)
+    }
 
-        mgr.repo.goto(&parent)?;
-        self.write(mgr)?;
+    pub fn depth(&self) -> usize {
+        self.next.len()
+    }
 
-        Ok(MoveResult::moved(&head, &parent))
+    pub fn queued(&self) -> Vec<Oid> {
+        self.next
+            .to_vec()
+            .into_iter()
+            .map(|commit| commit.0)
+            .collect()
     }
 
-    pub fn next(&mut self, mgr: &Manager) -> Result<MoveResult, Error> {
-        let head: Commit = mgr.repo.head_commit()?;
+    pub fn is_at_end(&self) -> bool {
+        matches!(self.next.as_ref(), Unrealised::Stop)
+    }
+
+    /// Move up to `steps` commits towards the root, stopping early at a root commit. Only the
+    /// final commit is checked out.
+    pub fn prev(
+        &mut self,
+        mgr: &Manager,
+        preserve: &[String],
+        steps: usize,
+    ) -> Result<Steps, Error> {
+        let head = mgr.repo.head_commit()?;
+        let mut new_head = head.clone();
+        let mut taken = 0;
+
+        while taken < steps && new_head.parent_count() > 0 {
+            let parent: Commit = mgr.repo.0.find_commit(new_head.parent_id(0)?)?.into();
+            let queue = mem::replace(&mut *self.next, Unrealised::Stop);
+            *self.next = queue.push_front(PlainOid(new_head.id()));
+            new_head = parent;
+            taken += 1;
+        }
+
+        if taken == 0 {
+            return Ok(Steps {
+                result: MoveResult::stationary(&head),
+                taken,
+                interrupted: None,
+            });
+        }
+
+        mgr.repo.goto(&new_head, preserve)?;
+        self.write(mgr, "prev", head.id(), new_head.id())?;
 
+        Ok(Steps {
+            result: MoveResult::moved(&head, &new_head),
+            taken,
+            interrupted: None,
+        })
+    }
+
+    /// Produce the commit that [State::next] would move to, without moving there.
+    fn realise_next<'a>(
+        &self,
+        mgr: &'a Manager,
+        head: &Commit<'a>,
+        mergetool: Option<&MergeTool>,
+    ) -> Result<Option<Commit<'a>>, Error> {
         match self.next.as_ref() {
-            Unrealised::Commit { next, commit } => {
+            Unrealised::Commit { commit, .. } => {
                 let cherry: Commit = mgr.repo.0.find_commit(commit.0)?.into();
 
                 let new_head = if cherry.parent_count() == 1
@@ -355,66 +978,502 @@ impl State {
                 {
                     cherry
                 } else {
-                    head.cherry_pick(mgr.repo(), &cherry, false, false)?
+                    let committer = mgr.committer_override(None)?;
+                    head.cherry_pick(
+                        mgr.repo(),
+                        &cherry,
+                        false,
+                        false,
+                        &[],
+                        mergetool,
+                        committer.as_ref(),
+                    )?
                 };
 
-                self.next = next.clone();
+                Ok(Some(new_head))
+            }
+
+            Unrealised::Stop => Ok(None),
+        }
+    }
+
+    /// Check whether [State::next] would succeed without touching HEAD, the working directory or
+    /// the state.
+    pub fn check_next(&self, mgr: &Manager) -> Result<MoveResult, Error> {
+        let head: Commit = mgr.repo.head_commit()?;
 
-                mgr.repo.goto(&new_head)?;
-                self.write(mgr)?;
+        match self.realise_next(mgr, &head, None)? {
+            Some(new_head) => Ok(MoveResult::moved(&head, &new_head)),
+            None => Ok(MoveResult::stationary(&head)),
+        }
+    }
 
-                Ok(MoveResult::moved(&head, &new_head))
+    /// Move up to `steps` commits along the queue, stopping early at its end or when a commit
+    /// cannot be applied after at least one step was taken. Only the final commit is checked
+    /// out. The error that stopped the move early is returned alongside the steps taken.
+    pub fn next(
+        &mut self,
+        mgr: &Manager,
+        preserve: &[String],
+        mergetool: Option<&MergeTool>,
+        steps: usize,
+    ) -> Result<Steps, Error> {
+        let head: Commit = mgr.repo.head_commit()?;
+        let mut new_head = head.clone();
+        let mut taken = 0;
+        let mut interrupted = None;
+
+        while taken < steps {
+            let realised = match self.realise_next(mgr, &new_head, mergetool) {
+                Ok(realised) => realised,
+                Err(err) if taken > 0 => {
+                    interrupted = Some(err);
+                    break;
+                }
+                Err(err) => return Err(err),
+            };
+
+            match realised {
+                Some(commit) => {
+                    let queue = mem::replace(&mut *self.next, Unrealised::Stop);
+                    if let Some((_, rest)) = queue.pop_front() {
+                        *self.next = rest;
+                    }
+
+                    new_head = commit;
+                    taken += 1;
+                }
+
+                None => break,
             }
+        }
 
-            Unrealised::Stop => Ok(MoveResult::stationary(&head)),
+        if taken == 0 {
+            return Ok(Steps {
+                result: MoveResult::stationary(&head),
+                taken,
+                interrupted,
+            });
         }
+
+        let wip = mgr.protect(new_head.id())?;
+        mgr.repo.goto(&new_head, preserve)?;
+        self.write(mgr, "next", head.id(), new_head.id())?;
+        mgr.release(&wip)?;
+
+        Ok(Steps {
+            result: MoveResult::moved(&head, &new_head),
+            taken,
+            interrupted,
+        })
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn commit(
         &mut self,
         mgr: &Manager,
         msg: Option<String>,
+        template_file: Option<&path::Path>,
         use_index: bool,
+        full_context: bool,
+        date: Option<CommitDate>,
+        trailers: &[Trailer],
+        committer: Option<PlainSig>,
+        allow_empty: bool,
     ) -> Result<MoveResult, Error> {
         let tree = mgr.capture_tree(use_index)?;
         let head: Commit = mgr.repo.head_commit()?;
 
+        if !allow_empty && tree.id() == head.tree_id() {
+            return Err(Error::EmptyCommit);
+        }
+
         let msg = match msg {
             Some(msg) => msg,
             None => {
-                let diff = mgr
-                    .repo()
-                    .diff_tree_to_tree(Some(&head.tree()?), Some(&tree), None)?;
-                mgr.compose_commit_message(None, Some(&diff))?
+                let mut diff_opts = git2::DiffOptions::new();
+                if full_context {
+                    diff_opts.context_lines(FULL_CONTEXT_LINES);
+                }
+
+                let diff = mgr.repo().diff_tree_to_tree(
+                    Some(&head.tree()?),
+                    Some(&tree),
+                    Some(&mut diff_opts),
+                )?;
+                let template = mgr.commit_template(template_file)?;
+                let msg = mgr.compose_commit_message(template.clone(), Some(&diff))?;
+
+                match template {
+                    Some(template) => {
+                        let template =
+                            git2::message_prettify(template, Some('#'.try_into().unwrap()))?;
+
+                        // Like git, refuse to commit a template that was left as is. That is the
+                        // case when nothing but the template remains.
+                        let msg = strip_template(&msg, &template);
+                        if msg.trim().is_empty() {
+                            return Err(Error::TemplateUnchanged);
+                        }
+
+                        git2::message_prettify(msg, None)?
+                    }
+
+                    None => msg,
+                }
             }
         };
 
         let sig = mgr.repo.signature()?;
-        let new_head_commit = mgr.repo.commit(&sig, &sig, msg, &tree, [&head])?;
+        let author = mgr.author_override(None)?.unwrap_or_else(|| sig.clone());
+        let author = match date {
+            Some(CommitDate::At(time)) => Signature::new(
+                author.name().unwrap_or(""),
+                author.email().unwrap_or(""),
+                &time,
+            )?,
+            Some(CommitDate::Now) | None => author,
+        };
+        let committer = mgr.committer_override(committer)?.unwrap_or(sig);
+        let new_head_commit =
+            mgr.repo
+                .commit_with_trailers(&author, &committer, msg, trailers, &tree, [&head])?;
 
-        mgr.repo
-            .reset(new_head_commit.as_object(), ResetType::Mixed, None)?;
-        self.write(mgr)?;
+        let wip = mgr.protect(new_head_commit.id())?;
+        mgr.repo.reset_index_to(&new_head_commit)?;
+        self.write(mgr, "commit", head.id(), new_head_commit.id())?;
+        mgr.release(&wip)?;
 
         Ok(MoveResult::moved(&head, &new_head_commit))
     }
 
-    pub fn amend(&mut self, mgr: &Manager, use_index: bool) -> Result<MoveResult, Error> {
-        let new_tree = mgr.capture_tree(use_index)?;
-
-        let head = mgr.repo.head_commit()?;
-        let new_head_id = head.amend(None, None, None, None, None, Some(&new_tree))?;
-        let new_head = mgr.repo.0.find_commit(new_head_id)?;
+    pub fn cherry_pick(
+        &mut self,
+        mgr: &Manager,
+        rev: &str,
+        sign: bool,
+    ) -> Result<MoveResult, Error> {
+        let head: Commit = mgr.repo.head_commit()?;
+        let cherry = mgr.repo.find_commit(rev)?;
+
+        let committer = mgr.committer_override(None)?;
+        let new_head_commit = head.cherry_pick(
+            mgr.repo(),
+            &cherry,
+            sign,
+            false,
+            &[],
+            None,
+            committer.as_ref(),
+        )?;
 
+        let wip = mgr.protect(new_head_commit.id())?;
         mgr.repo
-            .0
-            .reset(new_head.as_object(), ResetType::Mixed, None)?;
-        self.write(mgr)?;
+            .goto(&new_head_commit, &mgr.preserve_paths(Vec::new())?)?;
+        self.write(mgr, "cherry-pick", head.id(), new_head_commit.id())?;
+        mgr.release(&wip)?;
+
+        Ok(MoveResult::moved(&head, &new_head_commit))
+    }
+
+    pub fn apply(&mut self, mgr: &Manager, patch_file: &path::Path) -> Result<MoveResult, Error> {
+        let contents = fs::read(patch_file)?;
+        let diff = git2::Diff::from_buffer(contents.as_slice())?;
+
+        let head: Commit = mgr.repo.head_commit()?;
+        let head_tree = head.tree()?;
+        let mut index = match mgr.repo.apply_to_tree(&head_tree, &diff, None) {
+            Ok(index) => index,
+            Err(err) => {
+                // Try the hunks one by one to tell which of them are at fault.
+                let summaries = diffs::hunk_summaries(&diff)?;
+                let failing = (0..summaries.len())
+                    .filter(|index| {
+                        let mut selected = vec![false; summaries.len()];
+                        selected[*index] = true;
+                        mgr.repo.apply_hunks(&head_tree, &diff, &selected).is_err()
+                    })
+                    .map(|index| summaries[index].as_str())
+                    .collect::<Vec<_>>();
+
+                if failing.is_empty() {
+                    return Err(err.into());
+                }
+
+                return Err(Error::PatchDoesNotApply {
+                    hunks: failing.join("\n"),
+                });
+            }
+        };
+        let tree = mgr.repo.find_tree(index.write_tree_to(&mgr.repo.0)?)?;
+
+        let text = String::from_utf8_lossy(&contents);
+        let msg = match patch_message(&text) {
+            Some(msg) => msg,
+            None => mgr.compose_commit_message(None, Some(&diff))?,
+        };
+
+        // Keep the author from the headers of `git format-patch` output
+        let sig = mgr.repo.signature()?;
+        let author =
+            match patch_header(&text, "From").and_then(|from| from.parse::<PlainSig>().ok()) {
+                Some(from) => {
+                    let when = patch_header(&text, "Date")
+                        .and_then(|date| parse_rfc2822_date(&date))
+                        .unwrap_or_else(|| sig.when());
+                    Signature::new(from.name.as_str(), from.email.as_str(), &when)?
+                }
+                None => mgr.author_override(None)?.unwrap_or_else(|| sig.clone()),
+            };
+        let committer = mgr.committer_override(None)?.unwrap_or(sig);
+        let new_head_commit = mgr.repo.commit(&author, &committer, msg, &tree, [&head])?;
+
+        let wip = mgr.protect(new_head_commit.id())?;
+        mgr.repo
+            .goto(&new_head_commit, &mgr.preserve_paths(Vec::new())?)?;
+        self.write(mgr, "apply", head.id(), new_head_commit.id())?;
+        mgr.release(&wip)?;
+
+        Ok(MoveResult::moved(&head, &new_head_commit))
+    }
+
+    pub fn amend(
+        &mut self,
+        mgr: &Manager,
+        use_index: bool,
+        select_hunks: bool,
+    ) -> Result<MoveResult, Error> {
+        let captured_tree = mgr.capture_tree(use_index)?;
+        let head = mgr.repo.head_commit()?;
+
+        let (new_tree, keep_index) = if select_hunks {
+            let diff =
+                mgr.repo
+                    .diff_tree_to_tree(Some(&head.tree()?), Some(&captured_tree), None)?;
+            let selected = mgr.select_hunks(&diff)?;
+            let new_tree = mgr.repo.apply_hunks(&head.tree()?, &diff, &selected)?;
+
+            // The hunks that were left out remain staged if they were taken from the index.
+            let index_tree_id = mgr.repo.index()?.write_tree_to(&mgr.repo.0)?;
+            (new_tree, index_tree_id == captured_tree.id())
+        } else {
+            (captured_tree, false)
+        };
+
+        // Like `git commit --amend`, keep the author. Only `amend --author` replaces it.
+        let committer = mgr.committer_override(None)?;
+        let new_head = head.amend_tree(mgr.repo(), &new_tree, committer.as_ref())?;
+        let wip = mgr.protect(new_head.id())?;
+
+        if keep_index {
+            mgr.repo.reset_soft_to(&new_head)?;
+        } else {
+            mgr.repo.reset_index_to(&new_head)?;
+        }
+        self.write(mgr, "amend", head.id(), new_head.id())?;
+        mgr.release(&wip)?;
 
         Ok(MoveResult::moved(&head, &new_head))
     }
 }
 
+/// Remove the commit template from the message if it was carried over unchanged as a whole.
+/// Lines of the template that also appear elsewhere or were edited are kept.
+fn strip_template(msg: &str, template: &str) -> String {
+    let template_lines = template.lines().map(str::trim_end).collect::<Vec<_>>();
+    let msg_lines = msg.lines().collect::<Vec<_>>();
+
+    let start = msg_lines.windows(template_lines.len()).position(|window| {
+        window
+            .iter()
+            .map(|line| line.trim_end())
+            .eq(template_lines.iter().copied())
+    });
+
+    match start {
+        Some(start) if !template_lines.is_empty() => msg_lines[..start]
+            .iter()
+            .chain(&msg_lines[start + template_lines.len()..])
+            .map(|line| format!("{line}\n"))
+            .collect(),
+
+        _ => msg.to_string(),
+    }
+}
+
+/// Lines of the mail header of a patch produced by `git format-patch`, up to the blank line that
+/// separates it from the message body
+fn patch_header_lines(patch: &str) -> impl Iterator<Item = &str> {
+    patch
+        .lines()
+        .take_while(|line| !line.is_empty() && !line.starts_with("diff --git") && *line != "---")
+}
+
+/// Find a header like `From:` in a patch produced by `git format-patch`. Folded headers are
+/// joined into a single line and RFC 2047 encoded words are decoded.
+fn patch_header(patch: &str, name: &str) -> Option<String> {
+    let mut lines = patch_header_lines(patch).skip_while(|line| {
+        line.strip_prefix(name)
+            .and_then(|line| line.strip_prefix(':'))
+            .is_none()
+    });
+
+    let mut value = lines.next()?[name.len() + 1..].trim().to_string();
+    for line in lines.take_while(|line| line.starts_with([' ', '\t'])) {
+        value.push(' ');
+        value.push_str(line.trim());
+    }
+
+    Some(decode_encoded_words(&value))
+}
+
+/// Extract the commit subject from the headers of a patch produced by `git format-patch`.
+fn patch_subject(patch: &str) -> Option<String> {
+    let subject = patch_header(patch, "Subject")?;
+
+    // Drop the "[PATCH n/m]" prefix
+    let subject = match subject.strip_prefix('[') {
+        Some(rest) => rest
+            .split_once(']')
+            .map_or(subject.as_str(), |(_, rest)| rest.trim()),
+        None => subject.as_str(),
+    };
+
+    if subject.is_empty() {
+        None
+    } else {
+        Some(subject.to_string())
+    }
+}
+
+/// Build the commit message from a patch produced by `git format-patch`: the subject followed by
+/// the message body between the mail header and the `---` line.
+fn patch_message(patch: &str) -> Option<String> {
+    let subject = patch_subject(patch)?;
+
+    let header_len = patch_header_lines(patch).count();
+    let body = patch
+        .lines()
+        .skip(header_len)
+        .skip_while(|line| line.is_empty())
+        .take_while(|line| !line.starts_with("diff --git") && *line != "---")
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    git2::message_prettify(format!("{subject}\n\n{body}"), None).ok()
+}
+
+/// Decode the RFC 2047 encoded words like `=?UTF-8?q?J=C3=B6rg?=` in a mail header. Words that
+/// can't be decoded are left as they are.
+fn decode_encoded_words(value: &str) -> String {
+    let mut decoded = String::new();
+    let mut rest = value;
+    let mut after_word = false;
+
+    while let Some(start) = rest.find("=?") {
+        let (before, candidate) = rest.split_at(start);
+
+        match decode_encoded_word(candidate) {
+            Some((text, len)) => {
+                // Whitespace between two encoded words is not part of the text
+                if !after_word || !before.trim().is_empty() {
+                    decoded.push_str(before);
+                }
+                decoded.push_str(&text);
+                rest = &candidate[len..];
+                after_word = true;
+            }
+
+            None => {
+                decoded.push_str(before);
+                decoded.push_str("=?");
+                rest = &candidate[2..];
+                after_word = false;
+            }
+        }
+    }
+
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Decode the encoded word at the start of `word`, returning the text and the length of the word
+fn decode_encoded_word(word: &str) -> Option<(String, usize)> {
+    let mut parts = word.strip_prefix("=?")?.splitn(3, '?');
+    let charset = parts.next()?;
+    let encoding = parts.next()?;
+    let (encoded, _) = parts.next()?.split_once("?=")?;
+
+    if [charset, encoding, encoded]
+        .iter()
+        .any(|part| part.contains(char::is_whitespace))
+    {
+        return None;
+    }
+
+    let bytes = match encoding {
+        "Q" | "q" => decode_q(encoded)?,
+        "B" | "b" => decode_base64(encoded)?,
+        _ => return None,
+    };
+
+    // A language may follow the charset, as in "UTF-8*de"
+    let text = match charset.split('*').next()?.to_ascii_lowercase().as_str() {
+        "utf-8" | "us-ascii" => String::from_utf8(bytes).ok()?,
+        "iso-8859-1" | "latin1" => bytes.iter().map(|byte| char::from(*byte)).collect(),
+        _ => return None,
+    };
+
+    let len = format!("=?{charset}?{encoding}?{encoded}?=").len();
+    Some((text, len))
+}
+
+/// Decode the "Q" encoding of RFC 2047, where `_` stands for a space and `=XX` for a byte
+fn decode_q(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut rest = text.as_bytes();
+
+    while let Some((byte, tail)) = rest.split_first() {
+        match byte {
+            b'_' => bytes.push(b' '),
+            b'=' => {
+                let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &tail[2..];
+                continue;
+            }
+            byte => bytes.push(*byte),
+        }
+
+        rest = tail;
+    }
+
+    Some(bytes)
+}
+
+/// Decode the "B" encoding of RFC 2047, which is base64
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut bytes = Vec::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for byte in text.trim_end_matches('=').bytes() {
+        let value = ALPHABET.iter().position(|candidate| *candidate == byte)? as u32;
+        buffer = (buffer << 6) | value;
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+
+    Some(bytes)
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub enum Unrealised {
     Stop,
@@ -424,15 +1483,1335 @@ pub enum Unrealised {
     },
 }
 
+impl Unrealised {
+    pub fn len(&self) -> usize {
+        let mut len = 0;
+        let mut current = self;
+
+        while let Unrealised::Commit { next, .. } = current {
+            len += 1;
+            current = next.as_ref();
+        }
+
+        len
+    }
+
+    /// Put a commit in front of the queue, making it the one that comes next
+    pub fn push_front(self, commit: PlainOid) -> Self {
+        Unrealised::Commit {
+            next: Box::new(self),
+            commit,
+        }
+    }
+
+    /// Take the commit that comes next off the queue
+    pub fn pop_front(self) -> Option<(PlainOid, Self)> {
+        match self {
+            Unrealised::Commit { next, commit } => Some((commit, *next)),
+            Unrealised::Stop => None,
+        }
+    }
+
+    /// Commits in the queue, starting with the one that comes next
+    pub fn to_vec(&self) -> Vec<PlainOid> {
+        let mut commits = Vec::new();
+        let mut current = self;
+
+        while let Unrealised::Commit { next, commit } = current {
+            commits.push(*commit);
+            current = next.as_ref();
+        }
+
+        commits
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct PlainSig {
     pub name: String,
     pub email: String,
 }
 
+impl std::str::FromStr for PlainSig {
+    type Err = String;
+
+    fn from_str(sig: &str) -> Result<Self, Self::Err> {
+        let (name, email) = sig
+            .trim()
+            .strip_suffix('>')
+            .and_then(|sig| sig.rsplit_once('<'))
+            .ok_or_else(|| format!("Expected \"Name <email>\", got {sig:?}"))?;
+
+        Ok(PlainSig {
+            name: name.trim().to_string(),
+            email: email.trim().to_string(),
+        })
+    }
+}
+
+/// Point in time to use for a commit's author date
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommitDate {
+    Now,
+    At(git2::Time),
+}
+
+impl std::str::FromStr for CommitDate {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let spec = spec.trim();
+        let bad_date =
+            || format!("Expected \"now\", \"@<unix> [+-HHMM]\" or an RFC 3339 date, got {spec:?}");
+
+        if spec == "now" {
+            return Ok(CommitDate::Now);
+        }
+
+        // Raw git format: @<seconds since epoch> followed by an optional offset
+        if let Some(raw) = spec.strip_prefix('@') {
+            let (seconds, offset) = match raw.split_once(' ') {
+                Some((seconds, offset)) => (seconds, parse_offset(offset.trim(), false)),
+                None => (raw, Some(0)),
+            };
+
+            let seconds = seconds.parse::<i64>().map_err(|_| bad_date())?;
+            let offset = offset.ok_or_else(bad_date)?;
+            return Ok(CommitDate::At(git2::Time::new(seconds, offset)));
+        }
+
+        // RFC 3339: YYYY-MM-DDTHH:MM:SS[.fraction](Z|+HH:MM|-HH:MM)
+        let (date, time) = spec.split_once(['T', 't', ' ']).ok_or_else(bad_date)?;
+
+        let date = date
+            .splitn(3, '-')
+            .map(|part| part.parse::<i64>().ok())
+            .collect::<Option<Vec<_>>>()
+            .filter(|parts| parts.len() == 3)
+            .ok_or_else(bad_date)?;
+
+        let (clock, offset) = match time.strip_suffix(['Z', 'z']) {
+            Some(clock) => (clock, Some(0)),
+            None => {
+                let split = time.rfind(['+', '-']).ok_or_else(bad_date)?;
+                (&time[..split], parse_offset(&time[split..], true))
+            }
+        };
+        let offset = offset.ok_or_else(bad_date)?;
+
+        let clock = clock.split('.').next().unwrap_or(clock);
+        let clock = clock
+            .splitn(3, ':')
+            .map(|part| part.parse::<i64>().ok())
+            .collect::<Option<Vec<_>>>()
+            .filter(|parts| parts.len() == 3)
+            .ok_or_else(bad_date)?;
+
+        let local_seconds =
+            seconds_from_civil(date[0], date[1], date[2], clock[0], clock[1], clock[2])
+                .ok_or_else(bad_date)?;
+        let seconds = local_seconds - i64::from(offset) * 60;
+
+        Ok(CommitDate::At(git2::Time::new(seconds, offset)))
+    }
+}
+
+/// Parse an RFC 2822 date as found in the `Date:` header of an e-mail, e.g.
+/// `Mon, 1 Jan 2024 12:30:00 +0100`.
+fn parse_rfc2822_date(date: &str) -> Option<git2::Time> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    // The day of the week is optional
+    let date = date.split_once(',').map_or(date, |(_, date)| date);
+
+    let mut parts = date.split_whitespace();
+    let day = parts.next()?.parse::<i64>().ok()?;
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|name| *name == month)? as i64 + 1;
+    let year = parts.next()?.parse::<i64>().ok()?;
+
+    let clock = parts
+        .next()?
+        .splitn(3, ':')
+        .map(|part| part.parse::<i64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let (hour, minute, second) = match clock.as_slice() {
+        [hour, minute] => (*hour, *minute, 0),
+        [hour, minute, second] => (*hour, *minute, *second),
+        _ => return None,
+    };
+
+    let offset = parse_offset(parts.next()?, false)?;
+
+    let local_seconds = seconds_from_civil(year, month, day, hour, minute, second)?;
+    Some(git2::Time::new(
+        local_seconds - i64::from(offset) * 60,
+        offset,
+    ))
+}
+
+/// Parse a time zone offset like `+0130` (or `+01:30` if `with_colon` is set) into minutes.
+fn parse_offset(offset: &str, with_colon: bool) -> Option<i32> {
+    let (sign, digits) = match (offset.get(..1)?, offset.get(1..)?) {
+        ("+", digits) => (1, digits),
+        ("-", digits) => (-1, digits),
+        _ => return None,
+    };
+
+    let (hours, minutes) = if with_colon {
+        digits.split_once(':')?
+    } else {
+        (digits.get(..2)?, digits.get(2..)?)
+    };
+
+    let hours = hours
+        .parse::<i32>()
+        .ok()
+        .filter(|hours| (0..24).contains(hours))?;
+    let minutes = minutes
+        .parse::<i32>()
+        .ok()
+        .filter(|minutes| (0..60).contains(minutes))?;
+
+    Some(sign * (hours * 60 + minutes))
+}
+
+/// Seconds since 1970-01-01 00:00:00 for the given date and time of day, or `None` if any of
+/// the fields is out of range.
+fn seconds_from_civil(
+    year: i64,
+    month: i64,
+    day: i64,
+    hour: i64,
+    minute: i64,
+    second: i64,
+) -> Option<i64> {
+    let is_leap_year = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if is_leap_year => 29,
+        2 => 28,
+        _ => return None,
+    };
+
+    if !(1..=days_in_month).contains(&day)
+        || !(0..24).contains(&hour)
+        || !(0..60).contains(&minute)
+        || !(0..60).contains(&second)
+    {
+        return None;
+    }
+
+    Some(days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+/// Number of days since 1970-01-01 for the given proleptic Gregorian calendar date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct CommitInfo {
     pub author: PlainSig,
     pub committer: PlainSig,
     pub message: String,
 }
+
+/// Individual field of [CommitInfo] that can be edited on its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommitInfoField {
+    AuthorName,
+    AuthorEmail,
+    CommitterName,
+    CommitterEmail,
+    Message,
+}
+
+impl CommitInfoField {
+    pub const ALL: [CommitInfoField; 5] = [
+        CommitInfoField::AuthorName,
+        CommitInfoField::AuthorEmail,
+        CommitInfoField::CommitterName,
+        CommitInfoField::CommitterEmail,
+        CommitInfoField::Message,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CommitInfoField::AuthorName => "author name",
+            CommitInfoField::AuthorEmail => "author email",
+            CommitInfoField::CommitterName => "committer name",
+            CommitInfoField::CommitterEmail => "committer email",
+            CommitInfoField::Message => "message",
+        }
+    }
+
+    /// Select a field by its 1-based position in [CommitInfoField::ALL] or by its name.
+    pub fn select(choice: &str) -> Option<Self> {
+        let choice = choice.trim();
+
+        match choice.parse::<usize>() {
+            Ok(index) => index
+                .checked_sub(1)
+                .and_then(|index| Self::ALL.get(index))
+                .copied(),
+            Err(_) => Self::ALL
+                .into_iter()
+                .find(|field| field.name().eq_ignore_ascii_case(choice)),
+        }
+    }
+
+    pub fn get<'a>(&self, info: &'a CommitInfo) -> &'a String {
+        match self {
+            CommitInfoField::AuthorName => &info.author.name,
+            CommitInfoField::AuthorEmail => &info.author.email,
+            CommitInfoField::CommitterName => &info.committer.name,
+            CommitInfoField::CommitterEmail => &info.committer.email,
+            CommitInfoField::Message => &info.message,
+        }
+    }
+
+    pub fn get_mut<'a>(&self, info: &'a mut CommitInfo) -> &'a mut String {
+        match self {
+            CommitInfoField::AuthorName => &mut info.author.name,
+            CommitInfoField::AuthorEmail => &mut info.author.email,
+            CommitInfoField::CommitterName => &mut info.committer.name,
+            CommitInfoField::CommitterEmail => &mut info.committer.email,
+            CommitInfoField::Message => &mut info.message,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{checkout, editor_buffer, manager, set_editor, stack, EDITOR_SAVE};
+
+    #[test]
+    fn commit_template_prefills_the_message() {
+        let (mgr, dir) = manager();
+        let template = dir.path().join("template");
+        fs::write(&template, "Summary:\n# Explain why\n").unwrap();
+        mgr.repo
+            .config()
+            .unwrap()
+            .set_str("commit.template", template.to_str().unwrap())
+            .unwrap();
+
+        set_editor(
+            &mgr,
+            &format!("{EDITOR_SAVE}sed -i 's/^Summary:$/Summary: fixed it/' \"$1\"\n"),
+        );
+
+        let template = mgr.commit_template(None).unwrap();
+        let message = mgr.compose_commit_message(template, None).unwrap();
+
+        assert!(editor_buffer(&mgr).starts_with("Summary:\n# Explain why\n"));
+        assert_eq!(message, "Summary: fixed it\n");
+    }
+
+    #[test]
+    fn commit_template_file_overrides_config() {
+        let (mgr, dir) = manager();
+        let template = dir.path().join("template");
+        fs::write(&template, "From file\n").unwrap();
+
+        assert_eq!(mgr.commit_template(None).unwrap(), None);
+        assert_eq!(
+            mgr.commit_template(Some(&template)).unwrap().as_deref(),
+            Some("From file\n")
+        );
+    }
+
+    #[test]
+    fn strip_template_only_as_a_whole() {
+        let template = "Summary:\n\nSigned-off-by: Jane <jane@example.com>\n";
+        assert_eq!(strip_template(template, template), "");
+        assert_eq!(
+            strip_template(&format!("Fix it\n\n{template}"), template),
+            "Fix it\n\n"
+        );
+
+        // Lines that repeat parts of an edited template are kept
+        let msg = "Fix it\n\nSigned-off-by: Jane <jane@example.com>\n";
+        assert_eq!(strip_template(msg, template), msg);
+    }
+
+    /// Commit a change to `a` with the template `template` and the editor script `script`
+    fn commit_with_template(
+        mgr: &Manager,
+        dir: &temp_dir::TempDir,
+        template: &str,
+        script: &str,
+    ) -> Result<MoveResult, Error> {
+        stack(mgr);
+        fs::write(dir.path().join("a"), "changed\n").unwrap();
+
+        let template_file = dir.path().join("template");
+        fs::write(&template_file, template).unwrap();
+        set_editor(mgr, script);
+
+        State::read(mgr).unwrap().commit(
+            mgr,
+            None,
+            Some(&template_file),
+            false,
+            false,
+            None,
+            &[],
+            None,
+            false,
+        )
+    }
+
+    #[test]
+    fn commit_refuses_an_unchanged_template() {
+        let (mgr, dir) = manager();
+        let result = commit_with_template(&mgr, &dir, "Summary:\n# Explain why\n", "true\n");
+
+        assert!(matches!(result, Err(Error::TemplateUnchanged)));
+        assert_eq!(mgr.repo.head_commit().unwrap().message(), Some("a"));
+    }
+
+    #[test]
+    fn commit_keeps_an_edited_template() {
+        let (mgr, dir) = manager();
+        commit_with_template(
+            &mgr,
+            &dir,
+            "Summary:\n# Explain why\n",
+            "sed -i 's/^Summary:$/Summary: fixed it/' \"$1\"\n",
+        )
+        .unwrap();
+
+        let head = mgr.repo.head_commit().unwrap();
+        assert_eq!(head.message(), Some("Summary: fixed it\n"));
+    }
+
+    #[test]
+    fn depth_follows_navigation() {
+        let (mgr, _dir) = manager();
+        stack(&mgr);
+
+        let mut state = State::read(&mgr).unwrap();
+        assert_eq!(state.depth(), 0);
+        assert!(state.is_at_end());
+
+        state.prev(&mgr, &[], 2).unwrap();
+        assert_eq!(state.depth(), 2);
+
+        state.next(&mgr, &[], None, 1).unwrap();
+        assert_eq!(state.depth(), 1);
+        assert!(!state.is_at_end());
+
+        // The queue is persisted
+        assert_eq!(State::read(&mgr).unwrap().depth(), 1);
+    }
+
+    #[test]
+    fn unrealised_is_a_queue() {
+        let oids = (1..=3u8)
+            .map(|n| PlainOid(Oid::from_bytes(&[n; 20]).unwrap()))
+            .collect::<Vec<_>>();
+        let ids = |queue: &Unrealised| queue.to_vec().iter().map(|oid| oid.0).collect::<Vec<_>>();
+
+        let queue = Unrealised::Stop;
+        assert_eq!(queue.len(), 0);
+        assert!(queue.clone().pop_front().is_none());
+
+        let queue = oids
+            .iter()
+            .rev()
+            .fold(queue, |queue, oid| queue.push_front(*oid));
+        assert_eq!(queue.len(), 3);
+        assert_eq!(
+            ids(&queue),
+            oids.iter().map(|oid| oid.0).collect::<Vec<_>>()
+        );
+
+        let (first, queue) = queue.pop_front().unwrap();
+        assert_eq!(first.0, oids[0].0);
+        assert_eq!(queue.len(), 2);
+        assert_eq!(ids(&queue), [oids[1].0, oids[2].0]);
+    }
+
+    #[test]
+    fn plain_sig_from_str() {
+        let sig: PlainSig = " Jane Doe <jane@example.com> ".parse().unwrap();
+        assert_eq!(
+            sig,
+            PlainSig {
+                name: "Jane Doe".to_string(),
+                email: "jane@example.com".to_string(),
+            }
+        );
+
+        assert!("Jane Doe".parse::<PlainSig>().is_err());
+        assert!("jane@example.com>".parse::<PlainSig>().is_err());
+    }
+
+    #[test]
+    fn edit_replaces_only_the_author() {
+        let (mgr, _dir) = manager();
+        let commits = stack(&mgr);
+        let head = &commits[3];
+
+        let mut info = mgr.commit_info().unwrap();
+        info.author = "Jane Doe <jane@example.com>".parse().unwrap();
+        mgr.edit(&info).unwrap();
+
+        let new_head = mgr.repo.head_commit().unwrap();
+        assert_eq!(new_head.author().name(), Some("Jane Doe"));
+        assert_eq!(new_head.author().email(), Some("jane@example.com"));
+        assert_eq!(new_head.author().when(), head.author().when());
+        assert_eq!(new_head.committer().name(), head.committer().name());
+        assert_eq!(new_head.message(), head.message());
+        assert_eq!(new_head.tree_id(), head.tree_id());
+        assert_eq!(new_head.parent_id(0).unwrap(), commits[2].id());
+    }
+
+    #[test]
+    fn reword_recreates_the_commits_above() {
+        let (mgr, _dir) = manager();
+        let commits = stack(&mgr);
+
+        mgr.reword_commit(&commits[1], "Reworded\n", false).unwrap();
+
+        let head = mgr.repo.head_commit().unwrap();
+        assert_eq!(head.message(), commits[3].message());
+        assert_eq!(head.tree_id(), commits[3].tree_id());
+
+        let second = mgr.repo.0.find_commit(head.parent_id(0).unwrap()).unwrap();
+        assert_eq!(second.message(), commits[2].message());
+        assert_eq!(second.tree_id(), commits[2].tree_id());
+
+        let first = mgr
+            .repo
+            .0
+            .find_commit(second.parent_id(0).unwrap())
+            .unwrap();
+        assert_eq!(first.message(), Some("Reworded\n"));
+        assert_eq!(first.tree_id(), commits[1].tree_id());
+        assert_eq!(first.parent_id(0).unwrap(), commits[0].id());
+    }
+
+    #[test]
+    fn reword_refuses_commits_outside_of_head() {
+        let (mgr, _dir) = manager();
+        let commits = stack(&mgr);
+        let other = mgr.repo.commit_files(Some(&commits[0]), &[("c", "1\n")]);
+
+        assert!(matches!(
+            mgr.check_reword(&other, true),
+            Err(Error::NotAncestorOfHead { commit }) if commit == other.id()
+        ));
+    }
+
+    #[test]
+    fn reword_refuses_pushed_commits_without_force() {
+        let (mgr, _dir) = manager();
+        let commits = stack(&mgr);
+        mgr.repo
+            .reference("refs/remotes/origin/main", commits[2].id(), false, "test")
+            .unwrap();
+
+        assert!(matches!(
+            mgr.check_reword(&commits[1], false),
+            Err(Error::Repo(repo::Error::AlreadyPushed { commit })) if commit == commits[1].id()
+        ));
+        assert!(mgr.check_reword(&commits[3], false).is_ok());
+
+        mgr.reword_commit(&commits[1], "Reworded\n", true).unwrap();
+        assert_ne!(mgr.repo.head_commit().unwrap().id(), commits[3].id());
+    }
+
+    #[test]
+    fn write_refuses_a_state_changed_since_reading() {
+        let (mgr, _dir) = manager();
+        stack(&mgr);
+        let head = mgr.repo.head_commit().unwrap().id();
+
+        // Neither has been written yet
+        let mut first = State::read(&mgr).unwrap();
+        let mut second = State::read(&mgr).unwrap();
+        first.write(&mgr, "first", head, head).unwrap();
+        assert!(matches!(
+            second.write(&mgr, "second", head, head),
+            Err(Error::StateChanged)
+        ));
+
+        // Both were read from the same blob
+        let mut first = State::read(&mgr).unwrap();
+        let mut second = State::read(&mgr).unwrap();
+        first.prev(&mgr, &[], 1).unwrap();
+        assert!(matches!(
+            second.write(&mgr, "second", head, head),
+            Err(Error::StateChanged)
+        ));
+
+        // Writing again after a successful write is fine
+        first.write(&mgr, "first", head, head).unwrap();
+        assert_eq!(State::read(&mgr).unwrap().depth(), 1);
+
+        // Only the successful writes are logged, and none of them got lost
+        let log = State::read_log(&mgr).unwrap();
+        let operations = log
+            .lines()
+            .map(|line| line.split(' ').nth(1).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(operations, ["first", "prev", "first"]);
+    }
+
+    #[test]
+    fn forced_write_overwrites_a_changed_state() {
+        let (mut mgr, _dir) = manager();
+        stack(&mgr);
+        let head = mgr.repo.head_commit().unwrap().id();
+
+        let mut stale = State::read(&mgr).unwrap();
+        State::read(&mgr).unwrap().prev(&mgr, &[], 1).unwrap();
+
+        mgr.set_force_state(true);
+        stale.write(&mgr, "stale", head, head).unwrap();
+        assert_eq!(State::read(&mgr).unwrap().depth(), 0);
+    }
+
+    #[test]
+    fn commit_full_context_shows_whole_files() {
+        let (mgr, dir) = manager();
+        let lines = (1..=20).map(|n| format!("line {n}\n")).collect::<String>();
+        let base = mgr.repo.commit_files(None, &[("file", lines.as_str())]);
+        checkout(&mgr, &base);
+        set_editor(
+            &mgr,
+            &format!("{EDITOR_SAVE}sed -i '1s/^$/Change/' \"$1\"\n"),
+        );
+
+        let commit = |full_context| {
+            fs::write(dir.path().join("file"), lines.replace("line 20", "changed")).unwrap();
+
+            let mut state = State::read(&mgr).unwrap();
+            state
+                .commit(
+                    &mgr,
+                    None,
+                    None,
+                    false,
+                    full_context,
+                    None,
+                    &[],
+                    None,
+                    false,
+                )
+                .unwrap();
+            checkout(&mgr, &base);
+
+            editor_buffer(&mgr)
+        };
+
+        assert!(!commit(false).contains(" line 1\n"));
+        assert!(commit(true).contains(" line 1\n"));
+    }
+
+    #[test]
+    fn navigation_is_logged() {
+        let (mgr, _dir) = manager();
+        let commits = stack(&mgr);
+        assert_eq!(State::read_log(&mgr).unwrap(), "");
+
+        let mut state = State::read(&mgr).unwrap();
+        state.prev(&mgr, &[], 2).unwrap();
+        state.next(&mgr, &[], None, 1).unwrap();
+
+        let log = State::read_log(&mgr).unwrap();
+        let entries = log
+            .lines()
+            .map(|line| line.split(' ').skip(1).collect::<Vec<_>>().join(" "))
+            .collect::<Vec<_>>();
+        let (first, second, third) = (commits[1].id(), commits[2].id(), commits[3].id());
+        assert_eq!(
+            entries,
+            [
+                format!("prev {third} {first}"),
+                format!("next {first} {second}")
+            ]
+        );
+    }
+
+    #[test]
+    fn patch_subject_drops_the_prefix() {
+        let patch = "From: Jane <jane@example.com>\nSubject: [PATCH 2/3] Fix it\n\n---\n";
+        assert_eq!(patch_subject(patch).as_deref(), Some("Fix it"));
+
+        let patch = "Subject: Fix it\n---\nSubject: not a header\n";
+        assert_eq!(patch_subject(patch).as_deref(), Some("Fix it"));
+
+        assert_eq!(patch_subject("Subject: [PATCH]\n"), None);
+        assert_eq!(patch_subject("diff --git a/a b/a\nSubject: x\n"), None);
+    }
+
+    #[test]
+    fn patch_message_unfolds_the_subject_and_keeps_the_body() {
+        let patch = "From: Jane <jane@example.com>\n\
+                     Subject: [PATCH] Fix a bug that needs\n \
+                     a long subject\n\
+                     \n\
+                     The body explains\n\
+                     \n\
+                     why.\n\
+                     ---\n \
+                     a | 2 +-\n";
+        assert_eq!(
+            patch_message(patch).as_deref(),
+            Some("Fix a bug that needs a long subject\n\nThe body explains\n\nwhy.\n")
+        );
+
+        let patch = "Subject: [PATCH] Fix it\n\n---\n";
+        assert_eq!(patch_message(patch).as_deref(), Some("Fix it\n"));
+    }
+
+    #[test]
+    fn patch_header_decodes_encoded_words() {
+        let patch = "From: =?UTF-8?q?J=C3=B6rg_M=C3=BC?= =?UTF-8?B?bGxlcg==?= <j@example.com>\n\
+                     Subject: =?ISO-8859-1?Q?Caf=E9?= is =?x?y?z?=\n";
+        assert_eq!(
+            patch_header(patch, "From").as_deref(),
+            Some("Jörg Müller <j@example.com>")
+        );
+        assert_eq!(
+            patch_header(patch, "Subject").as_deref(),
+            Some("Café is =?x?y?z?=")
+        );
+    }
+
+    #[test]
+    fn parse_rfc2822_date_with_offset() {
+        let time = parse_rfc2822_date("Mon, 3 Jun 2024 12:34:56 +0200").unwrap();
+        assert_eq!(time.seconds(), 1717410896);
+        assert_eq!(time.offset_minutes(), 120);
+
+        let time = parse_rfc2822_date("3 Jun 2024 10:34 -0030").unwrap();
+        assert_eq!(time.seconds(), 1717410840 + 30 * 60);
+        assert_eq!(time.offset_minutes(), -30);
+
+        assert!(parse_rfc2822_date("Mon, 3 Foo 2024 12:34:56 +0200").is_none());
+        assert!(parse_rfc2822_date("Mon, 32 Jun 2024 12:34:56 +0200").is_none());
+        assert!(parse_rfc2822_date("Mon, 3 Jun 2024 24:00:00 +0200").is_none());
+        assert!(parse_rfc2822_date("Mon, 3 Jun 2024 12:34:56 +2400").is_none());
+    }
+
+    /// Write `git format-patch` style output for the change of `file` from `old` to `new`
+    fn format_patch(mgr: &Manager, file: &str, old: &str, new: &str) -> path::PathBuf {
+        let (repo, _dir) = Repo::temporary();
+        let old = repo.commit_files(None, &[(file, old)]);
+        let new = repo.commit_files(Some(&old), &[(file, new)]);
+        let diff = repo.diff_commits(Some(&old), &new, None).unwrap();
+
+        let patch = format!(
+            "From {} Mon Sep 17 00:00:00 2001\n\
+             From: Jane Doe <jane@example.com>\n\
+             Date: Mon, 3 Jun 2024 12:34:56 +0200\n\
+             Subject: [PATCH] Change {file}\n\
+             \n\
+             ---\n\
+             {}",
+            new.id(),
+            diffs::render(&diff).unwrap()
+        );
+
+        let path = mgr.dot_git_child("test.patch");
+        fs::write(&path, patch).unwrap();
+        path
+    }
+
+    #[test]
+    fn apply_keeps_the_patch_author() {
+        let (mgr, _dir) = manager();
+        let commits = stack(&mgr);
+        let patch = format_patch(&mgr, "b", "2\n", "patched\n");
+
+        let mut state = State::read(&mgr).unwrap();
+        state.apply(&mgr, &patch).unwrap();
+
+        let head = mgr.repo.head_commit().unwrap();
+        assert_eq!(head.parent_id(0).unwrap(), commits[3].id());
+        assert_eq!(head.message(), Some("Change b\n"));
+        assert_eq!(head.author().name(), Some("Jane Doe"));
+        assert_eq!(head.author().when().seconds(), 1717410896);
+        assert_eq!(head.committer().name(), Some("Test"));
+
+        let entry = head.tree().unwrap().get_name("b").unwrap().id();
+        assert_eq!(mgr.repo.find_blob(entry).unwrap().content(), b"patched\n");
+    }
+
+    #[test]
+    fn apply_reports_failing_hunks() {
+        let (mgr, _dir) = manager();
+        let commits = stack(&mgr);
+        let patch = format_patch(&mgr, "b", "unrelated\n", "patched\n");
+
+        let mut state = State::read(&mgr).unwrap();
+        match state.apply(&mgr, &patch) {
+            Err(Error::PatchDoesNotApply { hunks }) => assert!(hunks.starts_with("b @@")),
+            other => panic!("Unexpected result {other:?}"),
+        }
+        assert_eq!(mgr.repo.head_commit().unwrap().id(), commits[3].id());
+    }
+
+    #[test]
+    fn porcelain_move_result() {
+        let (mgr, _dir) = manager();
+        let commits = stack(&mgr);
+        let (from, to) = (commits[2].id(), commits[3].id());
+
+        let result = MoveResult::stationary(&commits[3]);
+        assert_eq!(
+            Porcelain(&result).to_string(),
+            format!("commit\t{to}\nauthor\tTest <test@example.com>\nsubject\ta\n")
+        );
+
+        let result = MoveResult::moved(&commits[2], &commits[3]);
+        assert_eq!(
+            Porcelain(&result).to_string(),
+            format!(
+                "previous\t{from}\ncommit\t{to}\nauthor\tTest <test@example.com>\nsubject\ta\n"
+            )
+        );
+    }
+
+    #[test]
+    fn commit_date_from_str() {
+        let at = |seconds, offset| Ok(CommitDate::At(git2::Time::new(seconds, offset)));
+
+        assert_eq!("now".parse(), Ok(CommitDate::Now));
+        assert_eq!("@1717410896".parse(), at(1717410896, 0));
+        assert_eq!("@1717410896 +0200".parse(), at(1717410896, 120));
+        assert_eq!("2024-06-03T10:34:56Z".parse(), at(1717410896, 0));
+        assert_eq!("2024-06-03T12:34:56+02:00".parse(), at(1717410896, 120));
+        assert_eq!("2024-06-03 09:04:56.123-01:30".parse(), at(1717410896, -90));
+        assert_eq!("2024-02-29T00:00:00Z".parse(), at(1709164800, 0));
+        assert_eq!("1969-12-31T23:59:59Z".parse(), at(-1, 0));
+
+        for spec in [
+            "yesterday",
+            "@soon",
+            "@1717410896 +02",
+            "2024-06-03",
+            "2024-06-03T10:34:56",
+            "2024-13-03T10:34:56Z",
+            "2023-02-29T10:34:56Z",
+            "2024-06-31T10:34:56Z",
+            "2024-06-03T24:00:00Z",
+            "2024-06-03T10:60:00Z",
+            "2024-06-03T10:34:56+24:00",
+        ] {
+            assert!(spec.parse::<CommitDate>().is_err(), "{spec} was accepted");
+        }
+    }
+
+    #[test]
+    fn commit_with_date() {
+        let (mgr, dir) = manager();
+        let commits = stack(&mgr);
+        let date = "2024-06-03T12:34:56+02:00".parse().unwrap();
+
+        fs::write(dir.path().join("a"), "changed\n").unwrap();
+        let mut state = State::read(&mgr).unwrap();
+        state
+            .commit(
+                &mgr,
+                Some("Dated".to_string()),
+                None,
+                false,
+                false,
+                Some(date),
+                &[],
+                None,
+                false,
+            )
+            .unwrap();
+
+        let head = mgr.repo.head_commit().unwrap();
+        assert_eq!(head.parent_id(0).unwrap(), commits[3].id());
+        assert_eq!(head.author().when(), git2::Time::new(1717410896, 120));
+        assert_ne!(head.committer().when(), head.author().when());
+    }
+
+    #[test]
+    fn preserve_paths_from_config_and_arguments() {
+        let (mgr, _dir) = manager();
+        assert!(mgr.preserve_paths(Vec::new()).unwrap().is_empty());
+
+        let mut config = mgr.repo.config().unwrap();
+        config
+            .set_multivar("unstacked.preservePaths", "^$", "local.cfg")
+            .unwrap();
+        config
+            .set_multivar("unstacked.preservePaths", "^$", "*.env")
+            .unwrap();
+
+        assert_eq!(
+            mgr.preserve_paths(vec!["extra".to_string()]).unwrap(),
+            ["local.cfg", "*.env", "extra"]
+        );
+    }
+
+    #[test]
+    fn commit_info_field_select() {
+        assert_eq!(
+            CommitInfoField::select("1"),
+            Some(CommitInfoField::AuthorName)
+        );
+        assert_eq!(
+            CommitInfoField::select(" 5 "),
+            Some(CommitInfoField::Message)
+        );
+        assert_eq!(
+            CommitInfoField::select("Committer Email"),
+            Some(CommitInfoField::CommitterEmail)
+        );
+        assert_eq!(CommitInfoField::select("0"), None);
+        assert_eq!(CommitInfoField::select("6"), None);
+        assert_eq!(CommitInfoField::select("author"), None);
+    }
+
+    #[test]
+    fn commit_info_field_get_mut() {
+        let (mgr, _dir) = manager();
+        stack(&mgr);
+
+        let mut info = mgr.commit_info().unwrap();
+        *CommitInfoField::CommitterEmail.get_mut(&mut info) = "jane@example.com".to_string();
+        assert_eq!(info.committer.email, "jane@example.com");
+        assert_eq!(
+            CommitInfoField::CommitterEmail.get(&info),
+            "jane@example.com"
+        );
+        assert_eq!(info.author.email, "test@example.com");
+
+        mgr.edit(&info).unwrap();
+        let head = mgr.repo.head_commit().unwrap();
+        assert_eq!(head.committer().email(), Some("jane@example.com"));
+        assert_eq!(head.author().email(), Some("test@example.com"));
+    }
+
+    #[test]
+    fn failing_editor_aborts() {
+        let (mgr, _dir) = manager();
+        set_editor(&mgr, "echo 'Half-written message' > \"$1\"\nexit 1\n");
+
+        let result = mgr.compose_commit_message(None, None);
+        assert!(matches!(result, Err(Error::EditorAborted)));
+        assert!(!mgr.commit_message_file().exists());
+    }
+
+    #[test]
+    fn amend_with_selected_hunks() {
+        let (mgr, dir) = manager();
+        let old = (1..=20).map(|n| format!("{n}\n")).collect::<String>();
+        let new = old.replacen("1\n", "first\n", 1).replace("20\n", "last\n");
+        let base = mgr.repo.commit_files(None, &[("a", old.as_str())]);
+        checkout(&mgr, &base);
+
+        // Drop the second hunk from the selection
+        fs::write(dir.path().join("a"), &new).unwrap();
+        set_editor(&mgr, "sed -i '/^1 /d' \"$1\"\n");
+
+        let mut state = State::read(&mgr).unwrap();
+        state.amend(&mgr, false, true).unwrap();
+
+        let head = mgr.repo.head_commit().unwrap();
+        assert_eq!(head.parent_count(), 0);
+        let blob = head.tree().unwrap().get_name("a").unwrap().id();
+        let expected = old.replacen("1\n", "first\n", 1);
+        assert_eq!(
+            mgr.repo.find_blob(blob).unwrap().content(),
+            expected.as_bytes()
+        );
+
+        // The rest stays in the working directory
+        assert_eq!(fs::read_to_string(dir.path().join("a")).unwrap(), new);
+        assert_eq!(mgr.repo.uncommitted_changes().unwrap(), (0, 1));
+    }
+
+    #[test]
+    fn amend_keeps_the_author() {
+        let (mgr, dir) = manager();
+        let commits = stack(&mgr);
+        let mut info = mgr.commit_info().unwrap();
+        info.author = "Jane Doe <jane@example.com>".parse().unwrap();
+        mgr.edit(&info).unwrap();
+
+        fs::write(dir.path().join("a"), "amended\n").unwrap();
+        State::read(&mgr)
+            .unwrap()
+            .amend(&mgr, false, false)
+            .unwrap();
+
+        let head = mgr.repo.head_commit().unwrap();
+        assert_eq!(head.parent_id(0).unwrap(), commits[2].id());
+        assert_eq!(head.author().name(), Some("Jane Doe"));
+        assert_eq!(head.author().when(), commits[3].author().when());
+    }
+
+    #[test]
+    fn signature_override_from_arguments_or_environment() {
+        let (mgr, _dir) = manager();
+        let jane: PlainSig = "Jane Doe <jane@example.com>".parse().unwrap();
+
+        let sig = mgr.committer_override(Some(jane)).unwrap().unwrap();
+        assert_eq!(sig.name(), Some("Jane Doe"));
+        assert_eq!(sig.email(), Some("jane@example.com"));
+
+        assert!(mgr.signature_override(None, None, None).unwrap().is_none());
+
+        let sig = mgr
+            .signature_override(None, Some("From Env".to_string()), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(sig.name(), Some("From Env"));
+        assert_eq!(sig.email(), Some("test@example.com"));
+    }
+
+    #[test]
+    fn commit_with_committer_override() {
+        let (mgr, dir) = manager();
+        stack(&mgr);
+        fs::write(dir.path().join("a"), "changed\n").unwrap();
+
+        let mut state = State::read(&mgr).unwrap();
+        state
+            .commit(
+                &mgr,
+                Some("Committed by Jane".to_string()),
+                None,
+                false,
+                false,
+                None,
+                &[],
+                Some("Jane Doe <jane@example.com>".parse().unwrap()),
+                false,
+            )
+            .unwrap();
+
+        let head = mgr.repo.head_commit().unwrap();
+        assert_eq!(head.committer().name(), Some("Jane Doe"));
+        assert_eq!(head.author().name(), Some("Test"));
+    }
+
+    #[test]
+    fn empty_commits_need_allow_empty() {
+        let (mgr, _dir) = manager();
+        let commits = stack(&mgr);
+
+        let mut state = State::read(&mgr).unwrap();
+        let mut commit = |allow_empty| {
+            state.commit(
+                &mgr,
+                Some("Empty".to_string()),
+                None,
+                false,
+                false,
+                None,
+                &[],
+                None,
+                allow_empty,
+            )
+        };
+
+        assert!(matches!(commit(false), Err(Error::EmptyCommit)));
+        assert_eq!(mgr.repo.head_commit().unwrap().id(), commits[3].id());
+
+        commit(true).unwrap();
+        let head = mgr.repo.head_commit().unwrap();
+        assert_eq!(head.parent_id(0).unwrap(), commits[3].id());
+        assert_eq!(head.tree_id(), commits[3].tree_id());
+    }
+
+    #[test]
+    fn check_next_leaves_everything_alone() {
+        let (mgr, _dir) = manager();
+        let commits = stack(&mgr);
+
+        let mut state = State::read(&mgr).unwrap();
+        state.prev(&mgr, &[], 2).unwrap();
+        let log = State::read_log(&mgr).unwrap();
+
+        match state.check_next(&mgr).unwrap() {
+            MoveResult::Moved { from, to, .. } => {
+                assert_eq!(from, commits[1].id());
+                assert_eq!(to, commits[2].id());
+            }
+            other => panic!("Unexpected result {other:?}"),
+        }
+
+        // A conflicting next commit is reported without moving
+        let diverged = mgr.repo.commit_files(Some(&commits[0]), &[("b", "y\n")]);
+        checkout(&mgr, &diverged);
+        assert!(state.check_next(&mgr).is_err());
+
+        assert_eq!(mgr.repo.head_commit().unwrap().id(), diverged.id());
+        assert_eq!(State::read(&mgr).unwrap().depth(), 2);
+        assert_eq!(State::read_log(&mgr).unwrap(), log);
+    }
+
+    #[test]
+    fn check_next_at_the_end() {
+        let (mgr, _dir) = manager();
+        let commits = stack(&mgr);
+
+        let state = State::read(&mgr).unwrap();
+        assert!(matches!(
+            state.check_next(&mgr).unwrap(),
+            MoveResult::Stationary { head, .. } if head == commits[3].id()
+        ));
+    }
+
+    #[test]
+    fn wip_refs_protect_new_commits() {
+        let (mgr, dir) = manager();
+        let commits = stack(&mgr);
+        assert!(mgr.wip_refs().unwrap().is_empty());
+
+        let name = mgr.protect(commits[1].id()).unwrap();
+        assert!(name.starts_with(WIP_REF_PREFIX));
+        assert_eq!(mgr.wip_refs().unwrap(), [(name.clone(), commits[1].id())]);
+
+        mgr.release(&name).unwrap();
+        assert!(mgr.wip_refs().unwrap().is_empty());
+
+        // Completed operations leave nothing behind
+        fs::write(dir.path().join("a"), "changed\n").unwrap();
+        let mut state = State::read(&mgr).unwrap();
+        state
+            .commit(
+                &mgr,
+                Some("Change".to_string()),
+                None,
+                false,
+                false,
+                None,
+                &[],
+                None,
+                false,
+            )
+            .unwrap();
+        assert!(mgr.wip_refs().unwrap().is_empty());
+    }
+
+    #[test]
+    fn show_diff_from_flag_or_config() {
+        let (mgr, _dir) = manager();
+        assert!(!mgr.show_diff(false).unwrap());
+        assert!(mgr.show_diff(true).unwrap());
+
+        let mut config = mgr.repo.config().unwrap();
+        config.set_bool("navigation.showDiff", true).unwrap();
+        assert!(mgr.show_diff(false).unwrap());
+
+        config.set_str("navigation.showDiff", "maybe").unwrap();
+        assert!(mgr.show_diff(false).is_err());
+    }
+
+    #[test]
+    fn cherry_pick_onto_head() {
+        let (mgr, dir) = manager();
+        let commits = stack(&mgr);
+        let other = mgr.repo.commit_files(Some(&commits[0]), &[("c", "new\n")]);
+
+        let mut state = State::read(&mgr).unwrap();
+        state
+            .cherry_pick(&mgr, &other.id().to_string(), false)
+            .unwrap();
+
+        let head = mgr.repo.head_commit().unwrap();
+        assert_eq!(head.parent_id(0).unwrap(), commits[3].id());
+        assert_eq!(head.message(), other.message());
+        assert_eq!(fs::read_to_string(dir.path().join("c")).unwrap(), "new\n");
+        assert!(State::read_log(&mgr).unwrap().contains(" cherry-pick "));
+
+        // Conflicts leave HEAD where it was
+        let conflicting = mgr.repo.commit_files(Some(&commits[0]), &[("a", "y\n")]);
+        assert!(state
+            .cherry_pick(&mgr, &conflicting.id().to_string(), false)
+            .is_err());
+        assert_eq!(mgr.repo.head_commit().unwrap().id(), head.id());
+    }
+
+    #[test]
+    fn queued_lists_the_next_commits_in_order() {
+        let (mgr, _dir) = manager();
+        let commits = stack(&mgr);
+
+        let mut state = State::read(&mgr).unwrap();
+        assert!(state.queued().is_empty());
+
+        state.prev(&mgr, &[], 2).unwrap();
+        assert_eq!(state.queued(), [commits[2].id(), commits[3].id()]);
+
+        state.next(&mgr, &[], None, 1).unwrap();
+        assert_eq!(state.queued(), [commits[3].id()]);
+    }
+
+    #[test]
+    fn next_stops_at_the_end_of_the_queue() {
+        let (mgr, _dir) = manager();
+        let commits = stack(&mgr);
+
+        let mut state = State::read(&mgr).unwrap();
+        let steps = state.prev(&mgr, &[], 2).unwrap();
+        assert_eq!(steps.taken, 2);
+        assert_eq!(mgr.repo.head_commit().unwrap().id(), commits[1].id());
+
+        let steps = state.next(&mgr, &[], None, 3).unwrap();
+        assert_eq!(steps.taken, 2);
+        assert!(steps.interrupted.is_none());
+        assert_eq!(mgr.repo.head_commit().unwrap().id(), commits[3].id());
+        assert!(state.is_at_end());
+    }
+
+    #[test]
+    fn next_by_depth_reaches_the_tip() {
+        let (mgr, _dir) = manager();
+        let commits = stack(&mgr);
+
+        let mut state = State::read(&mgr).unwrap();
+        state.prev(&mgr, &[], 3).unwrap();
+
+        let depth = state.depth();
+        let steps = state.next(&mgr, &[], None, depth).unwrap();
+        assert_eq!(steps.taken, 3);
+        assert_eq!(mgr.repo.head_commit().unwrap().id(), commits[3].id());
+        assert_eq!(state.depth(), 0);
+    }
+
+    #[test]
+    fn prev_stops_at_the_root() {
+        let (mgr, _dir) = manager();
+        let commits = stack(&mgr);
+
+        let mut state = State::read(&mgr).unwrap();
+        let steps = state.prev(&mgr, &[], 5).unwrap();
+        assert_eq!(steps.taken, 3);
+        assert_eq!(mgr.repo.head_commit().unwrap().id(), commits[0].id());
+    }
+
+    #[test]
+    fn next_reports_the_error_that_stopped_it() {
+        let (mgr, _dir) = manager();
+        let commits = stack(&mgr);
+
+        let mut state = State::read(&mgr).unwrap();
+        state.prev(&mgr, &[], 2).unwrap();
+
+        // The second commit still applies, but the third one changes the same line
+        let diverged = mgr.repo.commit_files(Some(&commits[1]), &[("a", "y\n")]);
+        checkout(&mgr, &diverged);
+
+        let steps = state.next(&mgr, &[], None, 2).unwrap();
+        assert_eq!(steps.taken, 1);
+        assert!(matches!(steps.interrupted, Some(Error::Commit(_))));
+        assert_eq!(state.depth(), 1);
+    }
+
+    #[test]
+    fn next_fails_if_no_step_was_taken() {
+        let (mgr, _dir) = manager();
+        let commits = stack(&mgr);
+
+        let mut state = State::read(&mgr).unwrap();
+        state.prev(&mgr, &[], 1).unwrap();
+
+        let diverged = mgr.repo.commit_files(Some(&commits[2]), &[("a", "y\n")]);
+        checkout(&mgr, &diverged);
+
+        assert!(state.next(&mgr, &[], None, 2).is_err());
+        assert_eq!(state.depth(), 1);
+    }
+
+    #[test]
+    fn stacks_keep_their_own_state() {
+        let (mgr, _dir) = manager();
+        let commits = stack(&mgr);
+        State::read(&mgr).unwrap().prev(&mgr, &[], 2).unwrap();
+
+        let result = mgr.switch_stack("feature", true).unwrap();
+        assert!(matches!(result, MoveResult::Stationary { head, .. } if head == commits[1].id()));
+        assert_eq!(mgr.active_stack().unwrap(), "feature");
+        assert_eq!(mgr.stacks().unwrap(), ["default", "feature"]);
+
+        let mut state = State::read(&mgr).unwrap();
+        assert!(state.queued().is_empty());
+        state.prev(&mgr, &[], 1).unwrap();
+        assert_eq!(mgr.repo.head_commit().unwrap().id(), commits[0].id());
+
+        mgr.switch_stack("default", false).unwrap();
+        assert_eq!(mgr.repo.head_commit().unwrap().id(), commits[1].id());
+        assert_eq!(
+            State::read(&mgr).unwrap().queued(),
+            [commits[2].id(), commits[3].id()]
+        );
+
+        mgr.switch_stack("feature", false).unwrap();
+        assert_eq!(mgr.repo.head_commit().unwrap().id(), commits[0].id());
+        mgr.switch_stack("feature", false).unwrap();
+        assert_eq!(mgr.repo.head_commit().unwrap().id(), commits[0].id());
+        assert_eq!(State::read(&mgr).unwrap().queued(), [commits[1].id()]);
+    }
+
+    #[test]
+    fn switch_stack_rejects_bad_names() {
+        let (mgr, _dir) = manager();
+        stack(&mgr);
+        mgr.switch_stack("feature", true).unwrap();
+
+        assert!(matches!(
+            mgr.switch_stack("feature", true),
+            Err(Error::StackExists { name }) if name == "feature"
+        ));
+        assert!(matches!(
+            mgr.switch_stack("default", true),
+            Err(Error::StackExists { .. })
+        ));
+        assert!(matches!(
+            mgr.switch_stack("missing", false),
+            Err(Error::UnknownStack { name }) if name == "missing"
+        ));
+        for name in ["a/b", "a..b", ""] {
+            assert!(matches!(
+                mgr.switch_stack(name, true),
+                Err(Error::InvalidStackName { .. })
+            ));
+        }
+        assert_eq!(mgr.active_stack().unwrap(), "feature");
+    }
+
+    #[test]
+    fn prev_by_stack_height_reaches_the_base() {
+        let (mgr, _dir) = manager();
+        let commits = stack(&mgr);
+        assert_eq!(mgr.stack_height().unwrap(), 3);
+
+        mgr.repo
+            .reference("refs/remotes/origin/main", commits[1].id(), false, "test")
+            .unwrap();
+        let height = mgr.stack_height().unwrap();
+        assert_eq!(height, 2);
+
+        let mut state = State::read(&mgr).unwrap();
+        state.prev(&mgr, &[], height).unwrap();
+        assert_eq!(mgr.repo.head_commit().unwrap().id(), commits[1].id());
+        assert_eq!(state.depth(), 2);
+        assert_eq!(mgr.stack_height().unwrap(), 0);
+    }
+}
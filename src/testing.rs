@@ -0,0 +1,95 @@
+//! Scaffolding shared by the tests of all modules
+
+use crate::{commit::Commit, repo::Repo, state::Manager};
+use git2::Diff;
+use std::fs;
+
+impl Repo {
+    /// Commit the given files with their contents on top of `parent`
+    pub fn commit_files<'a>(
+        &'a self,
+        parent: Option<&Commit<'a>>,
+        files: &[(&str, &str)],
+    ) -> Commit<'a> {
+        let base = match parent {
+            Some(parent) => parent.tree().unwrap(),
+            None => {
+                let empty = self.0.treebuilder(None).unwrap().write().unwrap();
+                self.0.find_tree(empty).unwrap()
+            }
+        };
+
+        let mut builder = git2::build::TreeUpdateBuilder::new();
+        for (path, contents) in files {
+            let blob = self.0.blob(contents.as_bytes()).unwrap();
+            builder.upsert(path, blob, git2::FileMode::Blob);
+        }
+        let tree = builder.create_updated(&self.0, &base).unwrap();
+        let tree = self.0.find_tree(tree).unwrap();
+
+        let sig = self.0.signature().unwrap();
+        let message = files.iter().map(|(path, _)| *path).collect::<Vec<_>>();
+        self.commit(&sig, &sig, message.join(" "), &tree, parent)
+            .unwrap()
+    }
+}
+
+/// Diff between two commits of files with the given contents
+pub fn diff_files<'a>(repo: &'a Repo, old: &[(&str, &str)], new: &[(&str, &str)]) -> Diff<'a> {
+    let old = repo.commit_files(None, old).tree().unwrap();
+    let new = repo.commit_files(None, new).tree().unwrap();
+    repo.diff_tree_to_tree(Some(&old), Some(&new), None)
+        .unwrap()
+}
+
+/// Twenty lines, changed at the start and the end to get two hunks
+pub fn two_hunks() -> (String, String) {
+    let old = (1..=20).map(|n| format!("{n}\n")).collect::<String>();
+    let new = old.replacen("1\n", "first\n", 1).replace("20\n", "last\n");
+    (old, new)
+}
+
+pub fn manager() -> (Manager, temp_dir::TempDir) {
+    let (repo, dir) = Repo::temporary();
+    (Manager::new(repo), dir)
+}
+
+/// Check out `commit`, discarding the working directory
+pub fn checkout(mgr: &Manager, commit: &Commit) {
+    mgr.repo()
+        .reset(commit.as_object(), git2::ResetType::Hard, None)
+        .unwrap();
+}
+
+/// Commit a stack of three commits on top of a base commit and check out the last one.
+pub fn stack(mgr: &Manager) -> Vec<Commit<'_>> {
+    let repo = mgr.repo();
+    let base = repo.commit_files(None, &[("a", "1\n"), ("b", "1\n")]);
+    let first = repo.commit_files(Some(&base), &[("a", "2\n")]);
+    let second = repo.commit_files(Some(&first), &[("b", "2\n")]);
+    let third = repo.commit_files(Some(&second), &[("a", "3\n")]);
+    checkout(mgr, &third);
+
+    vec![base, first, second, third]
+}
+
+/// Script the editor for `mgr` through `core.editor` of its repository
+pub fn set_editor(mgr: &Manager, script: &str) {
+    let path = mgr.repo().path().join("test-editor");
+    fs::write(&path, script).unwrap();
+
+    let editor = format!("sh '{}'", path.display());
+    mgr.repo()
+        .config()
+        .unwrap()
+        .set_str("core.editor", &editor)
+        .unwrap();
+}
+
+/// Contents of the file last handed to the editor, saved by [EDITOR_SAVE]
+pub fn editor_buffer(mgr: &Manager) -> String {
+    fs::read_to_string(mgr.repo().path().join("editor-buffer")).unwrap()
+}
+
+/// Editor script prefix that saves the buffer for [editor_buffer]
+pub const EDITOR_SAVE: &str = "cp \"$1\" \"$(dirname \"$1\")/editor-buffer\"\n";